@@ -0,0 +1,79 @@
+//! Local tar archives, which ADD unpacks instead of copying.
+
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// How a tar archive is compressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Compression {
+    None,
+    Gzip,
+    Xz,
+}
+
+fn open(path: &Path, compression: Compression) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(GzDecoder::new(file)),
+        Compression::Xz => Box::new(XzDecoder::new(file)),
+    })
+}
+
+/// Reads until `buf` is full or the input ends, returning the number of bytes read
+fn read_prefix(mut reader: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Detects a tar archive by its contents, as Docker does, rather than by its
+/// file name. Returns `None` for anything else, including compressed files
+/// that do not hold a tar archive.
+pub(crate) fn detect(path: &Path) -> io::Result<Option<Compression>> {
+    let mut magic = [0; 6];
+    let read = read_prefix(File::open(path)?, &mut magic)?;
+    let compression = if magic[..read].starts_with(GZIP_MAGIC) {
+        Compression::Gzip
+    } else if magic[..read].starts_with(XZ_MAGIC) {
+        Compression::Xz
+    } else {
+        Compression::None
+    };
+
+    // A tar header is 512 bytes, with "ustar" at offset 257
+    let mut header = [0; 512];
+    let read = match read_prefix(open(path, compression)?, &mut header) {
+        Ok(read) => read,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+        Err(_) => return Ok(None),
+    };
+    Ok((read == header.len() && &header[257..262] == b"ustar").then_some(compression))
+}
+
+/// Unpacks a tar archive into `dest`, creating it if needed. Entries that
+/// would land outside `dest` are skipped.
+pub(crate) fn extract(path: &Path, dest: &Path) -> io::Result<()> {
+    let compression = detect(path)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a tar archive", path.display()),
+        )
+    })?;
+    fs::create_dir_all(dest)?;
+    let mut archive = tar::Archive::new(open(path, compression)?);
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+    archive.unpack(dest)
+}
@@ -0,0 +1,167 @@
+//! A content-addressed cache of ADD downloads, so large files are fetched once.
+//!
+//! File contents live under `blobs/<algorithm>/<digest>`. For each URL, a
+//! small JSON record under `urls/` remembers which blob it produced along with
+//! the response's ETag and Last-Modified headers, which are used to ask the
+//! server whether the blob is still current.
+
+use crate::checksum::{Algorithm, Checksum, Hasher};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+/// What the cache knows about a URL
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UrlEntry {
+    pub(crate) checksum: Checksum,
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+    /// Name given by the server's Content-Disposition header
+    pub(crate) file_name: Option<String>,
+}
+
+/// What [`Cache::prune`] removed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pruned {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache { dir: dir.into() }
+    }
+
+    /// `dfrun` under the user's cache directory, e.g. `~/.cache/dfrun`
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("dfrun"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn blob_path(&self, checksum: &Checksum) -> PathBuf {
+        self.dir
+            .join("blobs")
+            .join(checksum.to_string().replace(':', "/"))
+    }
+
+    fn url_path(&self, url: &str) -> PathBuf {
+        let mut hasher = Hasher::new(Algorithm::Sha256);
+        hasher.update(url.as_bytes());
+        self.dir
+            .join("urls")
+            .join(format!("{}.json", hasher.finish().digest))
+    }
+
+    /// Returns the cached file with this checksum, if there is one
+    pub(crate) fn blob(&self, checksum: &Checksum) -> Option<PathBuf> {
+        let path = self.blob_path(checksum);
+        if !path.is_file() {
+            return None;
+        }
+        // Mark the blob as recently used so pruning keeps it
+        if let Ok(file) = File::options().append(true).open(&path) {
+            file.set_modified(SystemTime::now()).ok();
+        }
+        Some(path)
+    }
+
+    /// Returns what was recorded about `url` when it was last downloaded,
+    /// provided its blob is still cached
+    pub(crate) fn url_entry(&self, url: &str) -> Option<UrlEntry> {
+        let contents = fs::read_to_string(self.url_path(url)).ok()?;
+        let json: serde_json::Value = serde_json::from_str(&contents).ok()?;
+        let text = |key: &str| json.get(key)?.as_str().map(str::to_string);
+        let entry = UrlEntry {
+            checksum: text("checksum")?.parse().ok()?,
+            etag: text("etag"),
+            last_modified: text("last_modified"),
+            file_name: text("file_name"),
+        };
+        self.blob_path(&entry.checksum).is_file().then_some(entry)
+    }
+
+    /// Adds a downloaded file to the cache under its checksum and records it
+    /// as the content of `url`
+    pub(crate) fn store(&self, url: &str, file: &Path, entry: &UrlEntry) -> io::Result<()> {
+        let blob = self.blob_path(&entry.checksum);
+        if !blob.is_file() {
+            let parent = blob.parent().unwrap_or(&self.dir);
+            fs::create_dir_all(parent)?;
+            // Copy then rename so a concurrent run never sees a partial blob
+            let partial = parent.join(format!(".{}.part", process::id()));
+            if let Err(e) = fs::copy(file, &partial).and_then(|_| fs::rename(&partial, &blob)) {
+                fs::remove_file(&partial).ok();
+                return Err(e);
+            }
+        }
+
+        let record = serde_json::json!({
+            "url": url,
+            "checksum": entry.checksum.to_string(),
+            "etag": entry.etag,
+            "last_modified": entry.last_modified,
+            "file_name": entry.file_name,
+        });
+        let path = self.url_path(url);
+        fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+        fs::write(path, record.to_string())
+    }
+
+    /// Removes the least recently used files until the cache holds at most
+    /// `max_size` bytes. A `max_size` of 0 empties the cache.
+    pub fn prune(&self, max_size: u64) -> io::Result<Pruned> {
+        let mut blobs = Vec::new();
+        for algorithm in read_dir_or_empty(&self.dir.join("blobs"))? {
+            for blob in read_dir_or_empty(&algorithm)? {
+                let metadata = fs::metadata(&blob)?;
+                if metadata.is_file() {
+                    blobs.push((metadata.modified()?, metadata.len(), blob));
+                }
+            }
+        }
+        blobs.sort();
+
+        let mut size: u64 = blobs.iter().map(|(_, len, _)| len).sum();
+        let mut pruned = Pruned::default();
+        for (_, len, blob) in blobs {
+            if size <= max_size {
+                break;
+            }
+            fs::remove_file(&blob)?;
+            size -= len;
+            pruned.files += 1;
+            pruned.bytes += len;
+        }
+
+        // Forget URLs whose content is gone
+        for record in read_dir_or_empty(&self.dir.join("urls"))? {
+            let checksum = fs::read_to_string(&record)
+                .ok()
+                .and_then(|contents| serde_json::from_str::<serde_json::Value>(&contents).ok())
+                .and_then(|json| json.get("checksum")?.as_str()?.parse::<Checksum>().ok());
+            match checksum {
+                Some(checksum) if self.blob_path(&checksum).is_file() => {}
+                _ => fs::remove_file(&record)?,
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+fn read_dir_or_empty(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.map(|entry| Ok(entry?.path())).collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,196 @@
+//! The build context that COPY and ADD read local sources from, filtered by
+//! the context's `.dockerignore`, and the stage directories `COPY --from`
+//! reads from.
+
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Wildcards never match across `/`, except for `**`
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A `.dockerignore` line. `!` lines re-include paths excluded by earlier lines.
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+    pattern: Pattern,
+    negated: bool,
+}
+
+/// A file or directory found under a COPY source directory
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    /// Path relative to the source directory
+    pub(crate) relative: PathBuf,
+    pub(crate) is_dir: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BuildContext {
+    root: PathBuf,
+    /// What the root is, for error messages
+    description: String,
+    rules: Vec<IgnoreRule>,
+}
+
+impl BuildContext {
+    /// Opens the build context at `root`, reading its `.dockerignore` if there is one
+    pub(crate) fn open(root: &Path) -> io::Result<Self> {
+        let rules = match fs::read_to_string(root.join(".dockerignore")) {
+            Ok(contents) => parse_dockerignore(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(BuildContext {
+            root: root.to_path_buf(),
+            description: format!("build context {}", root.display()),
+            rules,
+        })
+    }
+
+    /// A directory read without any `.dockerignore` rules, such as a stage's root
+    pub(crate) fn unfiltered(root: &Path, description: String) -> Self {
+        BuildContext {
+            root: root.to_path_buf(),
+            description,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Whether a path relative to the context root is excluded by `.dockerignore`.
+    /// A path is excluded when the last rule matching it, or one of its parent
+    /// directories, is not a `!` rule.
+    pub(crate) fn is_ignored(&self, relative: &Path) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            // Only rules that could flip the current outcome matter
+            if rule.negated != ignored {
+                continue;
+            }
+            let matches = relative
+                .ancestors()
+                .filter(|path| !path.as_os_str().is_empty())
+                .any(|path| rule.pattern.matches_path_with(path, MATCH_OPTIONS));
+            if matches {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+
+    /// Resolves a COPY source, which may contain wildcards, to paths in the
+    /// context. Sources cannot reach outside the context: a leading `/` or
+    /// extra `..` components stay at its root.
+    pub(crate) fn resolve(&self, source: &str) -> io::Result<Vec<PathBuf>> {
+        let relative = clean_relative(source);
+        let mut matches = Vec::new();
+
+        if Pattern::escape(source) == source {
+            let path = self.root.join(&relative);
+            if path.exists() && !self.is_ignored(&relative) {
+                matches.push(path);
+            }
+        } else {
+            let pattern = format!(
+                "{}/{}",
+                Pattern::escape(self.root.to_string_lossy().trim_end_matches('/')),
+                relative.to_string_lossy()
+            );
+            let paths = glob::glob_with(&pattern, MATCH_OPTIONS)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            for path in paths.filter_map(Result::ok) {
+                let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                if !self.is_ignored(relative) {
+                    matches.push(path);
+                }
+            }
+        }
+
+        if matches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in {}", source, self.description),
+            ));
+        }
+        Ok(matches)
+    }
+
+    /// Lists everything under `dir` (a path inside the context), skipping
+    /// ignored paths. Parents come before their children.
+    pub(crate) fn walk(&self, dir: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        self.walk_into(dir, Path::new(""), &mut entries)?;
+        Ok(entries)
+    }
+
+    fn walk_into(&self, base: &Path, relative: &Path, entries: &mut Vec<Entry>) -> io::Result<()> {
+        let mut children: Vec<_> =
+            fs::read_dir(base.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            let child_relative = relative.join(child.file_name());
+            let context_relative = base
+                .join(&child_relative)
+                .strip_prefix(&self.root)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| child_relative.clone());
+            if self.is_ignored(&context_relative) {
+                continue;
+            }
+            let is_dir = child.path().is_dir();
+            entries.push(Entry {
+                relative: child_relative.clone(),
+                is_dir,
+            });
+            if is_dir {
+                self.walk_into(base, &child_relative, entries)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Normalizes a source path to one relative to the context root
+fn clean_relative(path: &str) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => cleaned.push(part),
+            Component::ParentDir => {
+                cleaned.pop();
+            }
+            _ => {}
+        }
+    }
+    cleaned
+}
+
+fn parse_dockerignore(contents: &str) -> io::Result<Vec<IgnoreRule>> {
+    let mut rules = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern.trim()),
+            None => (false, line),
+        };
+        let cleaned = clean_relative(pattern);
+        if cleaned.as_os_str().is_empty() {
+            continue;
+        }
+        let pattern = Pattern::new(&cleaned.to_string_lossy()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid .dockerignore pattern {}: {}", line, e),
+            )
+        })?;
+        rules.push(IgnoreRule { pattern, negated });
+    }
+    Ok(rules)
+}
@@ -0,0 +1,359 @@
+//! Fetches ADD URLs over HTTP(S) without relying on external tools.
+
+use crate::cache::{Cache, UrlEntry};
+use crate::checksum::{Algorithm, Checksum, Hasher};
+use crate::executor::Destination;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Attempts made before a download is given up on
+const ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled for each one after it
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_REDIRECTS: u32 = 10;
+
+/// Why an attempt failed, and whether trying again could help
+enum Failure {
+    Retry(io::Error),
+    Fatal(io::Error),
+}
+
+/// Downloads `url` to `dest`, returning the path of the file written.
+///
+/// Redirects are followed. The body is written to a temporary file next to
+/// the destination and renamed into place once complete, so a failed
+/// download never leaves a partial file behind. Connection errors, 429 and
+/// 5xx responses are retried with exponential backoff. With a `checksum`,
+/// content that does not match it is discarded and the download fails.
+///
+/// With a `cache`, a file whose checksum is already cached is not requested
+/// at all, and other URLs are only downloaded again when the server reports,
+/// via their ETag or Last-Modified header, that they changed.
+pub(crate) fn download(
+    url: &str,
+    dest: Destination,
+    checksum: Option<&Checksum>,
+    cache: Option<&Cache>,
+) -> io::Result<PathBuf> {
+    if let (Some(cache), Some(checksum)) = (cache, checksum) {
+        if let Some(blob) = cache.blob(checksum) {
+            let file_name = cache.url_entry(url).and_then(|entry| entry.file_name);
+            let path = target_path(dest, url, file_name);
+            install(&blob, &path)?;
+            return Ok(path);
+        }
+    }
+    // Only entries the server can confirm are still current are worth asking about
+    let cached = cache
+        .and_then(|cache| cache.url_entry(url))
+        .filter(|entry| entry.etag.is_some() || entry.last_modified.is_some())
+        .filter(|entry| checksum.is_none_or(|checksum| *checksum == entry.checksum));
+
+    let agent = ureq::AgentBuilder::new()
+        .redirects(MAX_REDIRECTS)
+        .timeout_connect(Duration::from_secs(30))
+        .try_proxy_from_env(true)
+        .build();
+    let request = Request {
+        agent: &agent,
+        url,
+        dest,
+        checksum,
+        cache,
+        cached: cached.as_ref(),
+    };
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match request.attempt() {
+            Ok(path) => return Ok(path),
+            Err(Failure::Retry(e)) if attempt < ATTEMPTS => {
+                eprintln!(
+                    "Download of {} failed ({}), retrying in {:.1}s",
+                    url,
+                    e,
+                    backoff.as_secs_f32()
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(Failure::Retry(e)) | Err(Failure::Fatal(e)) => return Err(e),
+        }
+    }
+}
+
+/// The file a download is written to: `dest` itself, or a file in it named
+/// by the server or else by the URL
+fn target_path(dest: Destination, url: &str, file_name: Option<String>) -> PathBuf {
+    match dest {
+        Destination::File(path) => path.to_path_buf(),
+        Destination::Dir(dir) => dir.join(file_name.unwrap_or_else(|| url_file_name(url))),
+    }
+}
+
+/// Copies a cached file to `path`, replacing it atomically
+fn install(blob: &Path, path: &Path) -> io::Result<()> {
+    let partial = partial_path(path);
+    let result = fs::copy(blob, &partial).and_then(|_| fs::rename(&partial, path));
+    if result.is_err() {
+        fs::remove_file(&partial).ok();
+    }
+    result
+}
+
+/// Everything one attempt at a download needs
+struct Request<'a> {
+    agent: &'a ureq::Agent,
+    url: &'a str,
+    dest: Destination<'a>,
+    checksum: Option<&'a Checksum>,
+    cache: Option<&'a Cache>,
+    /// A cached copy of the URL to revalidate instead of downloading again
+    cached: Option<&'a UrlEntry>,
+}
+
+impl Request<'_> {
+    fn attempt(&self) -> Result<PathBuf, Failure> {
+        let url = self.url;
+        let mut request = self.agent.get(url);
+        if let Some(cached) = self.cached {
+            if let Some(etag) = &cached.etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, response)) => {
+                let e = io::Error::other(format!(
+                    "{} returned {} {}",
+                    url,
+                    code,
+                    response.status_text()
+                ));
+                return Err(if code == 429 || code >= 500 {
+                    Failure::Retry(e)
+                } else {
+                    Failure::Fatal(e)
+                });
+            }
+            Err(ureq::Error::Transport(transport)) => {
+                let e = io::Error::other(format!("failed to fetch {}: {}", url, transport));
+                return Err(match transport.kind() {
+                    ureq::ErrorKind::InvalidUrl
+                    | ureq::ErrorKind::UnknownScheme
+                    | ureq::ErrorKind::TooManyRedirects
+                    | ureq::ErrorKind::BadHeader
+                    | ureq::ErrorKind::InvalidProxyUrl => Failure::Fatal(e),
+                    _ => Failure::Retry(e),
+                });
+            }
+        };
+
+        if let (304, Some(cache), Some(cached)) = (response.status(), self.cache, self.cached) {
+            let path = target_path(self.dest, url, cached.file_name.clone());
+            let blob = cache
+                .blob(&cached.checksum)
+                .ok_or_else(|| Failure::Retry(io::Error::other("cached file disappeared")))?;
+            install(&blob, &path).map_err(Failure::Fatal)?;
+            return Ok(path);
+        }
+
+        let header = |name: &str| response.header(name).map(str::to_string);
+        let file_name = response
+            .header("Content-Disposition")
+            .and_then(content_disposition_file_name);
+        let path = target_path(self.dest, url, file_name.clone());
+        let etag = header("ETag");
+        let last_modified = header("Last-Modified");
+        let length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok());
+
+        let partial = partial_path(&path);
+        let algorithm = self
+            .checksum
+            .map_or(Algorithm::Sha256, |checksum| checksum.algorithm);
+        let mut hasher = Hasher::new(algorithm);
+        let result = File::create(&partial).and_then(|file| {
+            copy_with_progress(response.into_reader(), file, &path, length, &mut hasher)
+        });
+        if let Err(e) = result {
+            fs::remove_file(&partial).ok();
+            // Interrupted transfers are worth retrying; local write errors are not
+            return Err(match e.kind() {
+                io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound => Failure::Fatal(e),
+                _ => Failure::Retry(e),
+            });
+        }
+        let actual = hasher.finish();
+        if let Some(expected) = self.checksum {
+            if actual != *expected {
+                fs::remove_file(&partial).ok();
+                return Err(Failure::Fatal(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "checksum mismatch for {}: expected {}, got {}",
+                        url, expected, actual
+                    ),
+                )));
+            }
+        }
+        if let Some(cache) = self.cache {
+            let entry = UrlEntry {
+                checksum: actual,
+                etag,
+                last_modified,
+                file_name,
+            };
+            // A download that cannot be cached is still a successful download
+            if let Err(e) = cache.store(url, &partial, &entry) {
+                eprintln!(
+                    "Warning: failed to cache {} in {}: {}",
+                    url,
+                    cache.dir().display(),
+                    e
+                );
+            }
+        }
+        fs::rename(&partial, &path).map_err(|e| {
+            fs::remove_file(&partial).ok();
+            Failure::Fatal(e)
+        })?;
+        Ok(path)
+    }
+}
+
+/// The temporary file a download is written to before being renamed to `path`
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.part", name, process::id()))
+}
+
+/// Copies the response body to `file`, feeding it to `hasher` and showing
+/// progress when stderr is a terminal
+fn copy_with_progress(
+    mut body: impl Read,
+    mut file: File,
+    path: &Path,
+    length: Option<u64>,
+    hasher: &mut Hasher,
+) -> io::Result<()> {
+    let show_progress = io::stderr().is_terminal();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut buf = vec![0; 64 * 1024];
+    let mut received = 0u64;
+    let mut last_report = Instant::now();
+    loop {
+        let read = match body.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        file.write_all(&buf[..read])?;
+        hasher.update(&buf[..read]);
+        received += read as u64;
+        if show_progress && last_report.elapsed() >= Duration::from_millis(100) {
+            eprint!("\r{}", progress_line(&name, received, length));
+            last_report = Instant::now();
+        }
+    }
+    if show_progress {
+        eprintln!("\r{}", progress_line(&name, received, length));
+    }
+    if let Some(length) = length {
+        if received < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("download ended after {} of {} bytes", received, length),
+            ));
+        }
+    }
+    file.sync_all()
+}
+
+fn progress_line(name: &str, received: u64, length: Option<u64>) -> String {
+    const MIB: f64 = 1024.0 * 1024.0;
+    match length {
+        Some(length) if length > 0 => format!(
+            "Downloading {}: {:.1}/{:.1} MiB ({}%)",
+            name,
+            received as f64 / MIB,
+            length as f64 / MIB,
+            received * 100 / length
+        ),
+        _ => format!("Downloading {}: {:.1} MiB", name, received as f64 / MIB),
+    }
+}
+
+/// Reads the file name from a Content-Disposition header, preferring the
+/// RFC 5987 `filename*` parameter. Only the final path component is used so
+/// a server cannot write outside the destination directory.
+fn content_disposition_file_name(header: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for param in header.split(';').skip(1) {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename" => plain = Some(value.trim_matches('"').to_string()),
+            "filename*" => {
+                // charset'language'percent-encoded-name
+                extended = value.splitn(3, '\'').nth(2).map(percent_decode);
+            }
+            _ => {}
+        }
+    }
+    let name = extended.or(plain)?;
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name.to_string()),
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Names a downloaded file after the last segment of its URL's path, like
+/// Docker, falling back to `download` when the path is empty
+pub(crate) fn url_file_name(url: &str) -> String {
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme
+        .split_once('/')
+        .and_then(|(_, path)| path.trim_end_matches('/').rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download")
+        .to_string()
+}
@@ -0,0 +1,142 @@
+use crate::expand::SubstitutionError;
+use crate::parser::{self, ParseError};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// A Dockerfile step that did not complete successfully
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedStep {
+    pub start_line: usize,
+    pub end_line: usize,
+    /// The instruction as written in the Dockerfile
+    pub instruction: String,
+    /// Exit code to report for the step
+    pub code: i32,
+    /// Why the step failed, e.g. `exit status: 2`
+    pub reason: String,
+}
+
+impl FailedStep {
+    /// Human readable line reference, e.g. `line 3` or `lines 3-5`
+    pub fn line_range(&self) -> String {
+        parser::line_range(self.start_line, self.end_line)
+    }
+}
+
+impl fmt::Display for FailedStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Step at Dockerfile {} failed with {}: {}",
+            self.line_range(),
+            self.reason,
+            self.instruction
+        )
+    }
+}
+
+/// Everything that can stop a run
+#[derive(Debug)]
+pub enum Error {
+    /// The Dockerfile does not exist
+    NotFound(PathBuf),
+    /// The Dockerfile exists but could not be read
+    Read(PathBuf, io::Error),
+    Parse(ParseError),
+    /// A step failed and `keep_going` was not set
+    StepFailed(FailedStep),
+    /// An ARG has no default, environment value or user input
+    MissingArg {
+        name: String,
+        interactive: bool,
+    },
+    /// The directory named by WORKDIR could not be created
+    Workdir(PathBuf, io::Error),
+    /// A variable substitution failed, e.g. `${VAR:?}` with VAR unset, or an
+    /// undefined variable was used with `strict`
+    Substitution(SubstitutionError),
+    /// The file of build args could not be read
+    BuildArgFile(PathBuf, io::Error),
+    /// `--target` names a stage the Dockerfile does not have
+    UnknownTarget(String),
+    /// The Dockerfile uses something the export format cannot express
+    Export(ParseError),
+    Io(io::Error),
+}
+
+impl Error {
+    /// Process exit code the CLI should use for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::StepFailed(step) => step.code,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(path) => write!(f, "Dockerfile not found at: {}", path.display()),
+            Error::Read(_, e) => write!(f, "Failed to open Dockerfile: {}", e),
+            Error::Parse(e) => write!(f, "Failed to parse Dockerfile: {}", e),
+            Error::StepFailed(step) => write!(f, "{}", step),
+            Error::MissingArg {
+                name,
+                interactive: true,
+            } => write!(f, "No value provided for ARG {}", name),
+            Error::MissingArg {
+                name,
+                interactive: false,
+            } => write!(
+                f,
+                "No value provided for ARG {} (non-interactive mode requires a default, --build-arg or environment variable)",
+                name
+            ),
+            Error::Workdir(path, e) => {
+                write!(f, "Failed to create WORKDIR {}: {}", path.display(), e)
+            }
+            Error::Substitution(e) => write!(f, "Failed to substitute variables at {}", e),
+            Error::BuildArgFile(path, e) => {
+                write!(f, "Failed to read build args from {}: {}", path.display(), e)
+            }
+            Error::UnknownTarget(target) => {
+                write!(f, "Target stage {} not found in the Dockerfile", target)
+            }
+            Error::Export(e) => write!(f, "Failed to export Dockerfile: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Read(_, e) | Error::Workdir(_, e) | Error::BuildArgFile(_, e) | Error::Io(e) => {
+                Some(e)
+            }
+            Error::Parse(e) | Error::Export(e) => Some(e),
+            Error::Substitution(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<SubstitutionError> for Error {
+    fn from(e: SubstitutionError) -> Self {
+        Error::Substitution(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
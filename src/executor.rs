@@ -0,0 +1,391 @@
+//! The side effects of running a Dockerfile, behind a trait so embedders can
+//! replace them.
+
+use crate::archive;
+use crate::cache::Cache;
+use crate::checksum::Checksum;
+use crate::download;
+use crate::git::{self, GitSource};
+use crate::parser::Instruction;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The state of the run a step executes in
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    /// The current WORKDIR
+    pub workdir: &'a Path,
+    /// Variables set by ENV and ARG so far, passed to child processes
+    pub env: &'a BTreeMap<String, String>,
+}
+
+/// Permissions and ownership for files placed by COPY and ADD
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CopyOptions {
+    /// From `--chmod`
+    pub mode: Option<u32>,
+    /// User and group ids from `--chown`
+    pub owner: Option<(u32, u32)>,
+}
+
+/// Where ADD puts a downloaded file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination<'a> {
+    /// This file
+    File(&'a Path),
+    /// A file in this directory, named by the response's Content-Disposition
+    /// header or else by the URL
+    Dir(&'a Path),
+}
+
+/// Carries out the actions a Dockerfile asks for.
+///
+/// Methods that start a process return its exit code; a non-zero code or an
+/// `Err` fails the step.
+pub trait Executor {
+    /// Called before the actions of each instruction, FROM lines included
+    fn start_instruction(&mut self, _instruction: &Instruction) {}
+
+    /// Whether actions are only described rather than carried out. The runner
+    /// then skips what needs earlier actions to have happened, such as
+    /// finding the files an earlier stage wrote.
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    /// Runs a shell form command
+    fn run_shell(&mut self, command: &str, context: &Context) -> io::Result<i32>;
+
+    /// Runs an exec form command: `argv[0]` is started directly with the rest
+    /// as its arguments, without a shell
+    fn run_exec(&mut self, argv: &[String], context: &Context) -> io::Result<i32>;
+
+    /// Runs a script that starts with a `#!` interpreter line
+    fn run_script(&mut self, script: &str, context: &Context) -> io::Result<i32>;
+
+    /// Downloads `url` to `dest`, returning the path of the file written. A
+    /// download that does not match `checksum` must fail without leaving a file.
+    fn download(
+        &mut self,
+        url: &str,
+        dest: Destination,
+        checksum: Option<&Checksum>,
+        options: &CopyOptions,
+        context: &Context,
+    ) -> io::Result<PathBuf>;
+
+    /// Creates a directory along with any missing parents
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Writes `contents` to a file, replacing it if it exists
+    fn write_file(&mut self, path: &Path, contents: &[u8], options: &CopyOptions)
+        -> io::Result<()>;
+
+    /// Copies a file, replacing the destination if it exists
+    fn copy_file(&mut self, from: &Path, to: &Path, options: &CopyOptions) -> io::Result<()>;
+
+    /// Clones a git repository and copies its files into the directory `dest`,
+    /// including the `.git` directory only with `keep_git_dir`
+    fn fetch_git(
+        &mut self,
+        repository: &GitSource,
+        dest: &Path,
+        keep_git_dir: bool,
+        context: &Context,
+    ) -> io::Result<()>;
+
+    /// Unpacks a local tar archive, optionally gzip or xz compressed, into the
+    /// directory `dest`
+    fn extract_archive(&mut self, archive: &Path, dest: &Path) -> io::Result<()>;
+}
+
+/// Runs commands with `bash -c`, downloads with a built-in HTTP client and
+/// clones with `git`
+#[derive(Debug, Default, Clone)]
+pub struct ShellExecutor {
+    /// Where downloads are cached. Without one every download is fetched again.
+    pub cache: Option<Cache>,
+}
+
+impl Executor for ShellExecutor {
+    fn run_shell(&mut self, command: &str, context: &Context) -> io::Result<i32> {
+        let status = Command::new("bash")
+            .arg("-c")
+            .arg(command)
+            .current_dir(context.workdir)
+            .envs(context.env)
+            .status()?;
+        // Processes killed by a signal have no exit code
+        Ok(status.code().unwrap_or(1))
+    }
+
+    fn run_exec(&mut self, argv: &[String], context: &Context) -> io::Result<i32> {
+        let (program, args) = argv
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty exec form"))?;
+        let status = Command::new(program)
+            .args(args)
+            .current_dir(context.workdir)
+            .envs(context.env)
+            .status()?;
+        Ok(status.code().unwrap_or(1))
+    }
+
+    fn run_script(&mut self, script: &str, context: &Context) -> io::Result<i32> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos())
+            .unwrap_or_default();
+        let path = env::temp_dir().join(format!("dfrun-script-{}-{}", process::id(), nanos));
+        fs::write(&path, script)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        let status = Command::new(&path)
+            .current_dir(context.workdir)
+            .envs(context.env)
+            .status();
+        fs::remove_file(&path).ok();
+        Ok(status?.code().unwrap_or(1))
+    }
+
+    fn download(
+        &mut self,
+        url: &str,
+        dest: Destination,
+        checksum: Option<&Checksum>,
+        options: &CopyOptions,
+        _context: &Context,
+    ) -> io::Result<PathBuf> {
+        let path = download::download(url, dest, checksum, self.cache.as_ref())?;
+        apply_copy_options(&path, options)?;
+        Ok(path)
+    }
+
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write_file(
+        &mut self,
+        path: &Path,
+        contents: &[u8],
+        options: &CopyOptions,
+    ) -> io::Result<()> {
+        fs::write(path, contents)?;
+        apply_copy_options(path, options)
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path, options: &CopyOptions) -> io::Result<()> {
+        fs::copy(from, to)?;
+        apply_copy_options(to, options)
+    }
+
+    fn fetch_git(
+        &mut self,
+        repository: &GitSource,
+        dest: &Path,
+        keep_git_dir: bool,
+        context: &Context,
+    ) -> io::Result<()> {
+        git::fetch(repository, dest, keep_git_dir, context)
+    }
+
+    fn extract_archive(&mut self, archive: &Path, dest: &Path) -> io::Result<()> {
+        archive::extract(archive, dest)
+    }
+}
+
+/// Prints the actions a run would take instead of taking them, for `--dry-run`.
+/// Nothing is written, downloaded or started; files are only read to
+/// resolve COPY and ADD sources.
+#[derive(Debug)]
+pub struct PlanExecutor<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PlanExecutor<W> {
+    pub fn new(out: W) -> Self {
+        PlanExecutor { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn action(&mut self, action: String) -> io::Result<()> {
+        writeln!(self.out, "  {}", action)
+    }
+
+    /// Prints a command along with the WORKDIR and variables it runs with
+    fn command(&mut self, what: &str, command: &str, context: &Context) -> io::Result<i32> {
+        let mut action = format!("{} in {}", what, context.workdir.display());
+        if !context.env.is_empty() {
+            let env: Vec<String> = context
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            action.push_str(&format!(" with {}", env.join(" ")));
+        }
+        if command.contains('\n') {
+            self.action(format!("{}:", action))?;
+            for line in command.lines() {
+                writeln!(self.out, "    {}", line)?;
+            }
+        } else {
+            self.action(format!("{}: {}", action, command))?;
+        }
+        Ok(0)
+    }
+}
+
+/// Describes `--chmod` and `--chown` settings, e.g. ` (mode 755, owner 0:0)`
+fn describe_copy_options(options: &CopyOptions) -> String {
+    let mut settings = Vec::new();
+    if let Some(mode) = options.mode {
+        settings.push(format!("mode {:o}", mode));
+    }
+    if let Some((uid, gid)) = options.owner {
+        settings.push(format!("owner {}:{}", uid, gid));
+    }
+    if settings.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", settings.join(", "))
+    }
+}
+
+impl<W: Write> Executor for PlanExecutor<W> {
+    fn dry_run(&self) -> bool {
+        true
+    }
+
+    fn start_instruction(&mut self, instruction: &Instruction) {
+        writeln!(
+            self.out,
+            "{}: {}",
+            instruction.line_range(),
+            instruction.original
+        )
+        .ok();
+    }
+
+    fn run_shell(&mut self, command: &str, context: &Context) -> io::Result<i32> {
+        self.command("run", command, context)
+    }
+
+    fn run_exec(&mut self, argv: &[String], context: &Context) -> io::Result<i32> {
+        self.command("exec", &format!("{:?}", argv), context)
+    }
+
+    fn run_script(&mut self, script: &str, context: &Context) -> io::Result<i32> {
+        self.command("run script", script, context)
+    }
+
+    fn download(
+        &mut self,
+        url: &str,
+        dest: Destination,
+        checksum: Option<&Checksum>,
+        options: &CopyOptions,
+        _context: &Context,
+    ) -> io::Result<PathBuf> {
+        let path = match dest {
+            Destination::File(path) => path.to_path_buf(),
+            // The server may name the file differently once it is fetched
+            Destination::Dir(dir) => dir.join(download::url_file_name(url)),
+        };
+        let checksum =
+            checksum.map_or(String::new(), |checksum| format!(", checking {}", checksum));
+        self.action(format!(
+            "download {} to {}{}{}",
+            url,
+            path.display(),
+            checksum,
+            describe_copy_options(options)
+        ))?;
+        Ok(path)
+    }
+
+    fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+        self.action(format!("create directory {}", path.display()))
+    }
+
+    fn write_file(
+        &mut self,
+        path: &Path,
+        contents: &[u8],
+        options: &CopyOptions,
+    ) -> io::Result<()> {
+        self.action(format!(
+            "write {} bytes to {}{}",
+            contents.len(),
+            path.display(),
+            describe_copy_options(options)
+        ))
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path, options: &CopyOptions) -> io::Result<()> {
+        self.action(format!(
+            "copy {} to {}{}",
+            from.display(),
+            to.display(),
+            describe_copy_options(options)
+        ))
+    }
+
+    fn fetch_git(
+        &mut self,
+        repository: &GitSource,
+        dest: &Path,
+        keep_git_dir: bool,
+        _context: &Context,
+    ) -> io::Result<()> {
+        let mut action = format!(
+            "clone {} at {} into {}",
+            repository.url,
+            repository.reference.as_deref().unwrap_or("HEAD"),
+            dest.display()
+        );
+        if let Some(subdir) = &repository.subdir {
+            action.push_str(&format!(" (only {})", subdir));
+        }
+        if keep_git_dir {
+            action.push_str(" with .git");
+        }
+        self.action(action)
+    }
+
+    fn extract_archive(&mut self, archive: &Path, dest: &Path) -> io::Result<()> {
+        self.action(format!(
+            "extract {} into {}",
+            archive.display(),
+            dest.display()
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn apply_copy_options(path: &Path, options: &CopyOptions) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = options.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if let Some((uid, gid)) = options.owner {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_copy_options(_path: &Path, _options: &CopyOptions) -> io::Result<()> {
+    Ok(())
+}
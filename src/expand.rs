@@ -0,0 +1,410 @@
+//! Variable substitution in instruction arguments, following the grammar
+//! Docker uses for ENV, ARG defaults, WORKDIR, COPY, ADD and FROM.
+
+use std::fmt;
+use std::io;
+
+/// A substitution that could not be carried out, such as `${VAR:?}` with
+/// `VAR` unset or a `${` without its closing brace
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubstitutionError {
+    /// First line of the instruction
+    pub line: usize,
+    /// Column of the offending `$` within the instruction (1-based, with line
+    /// continuations joined)
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SubstitutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for SubstitutionError {}
+
+impl From<SubstitutionError> for io::Error {
+    fn from(e: SubstitutionError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Where in the expanded string a substitution failed, and why
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Invalid {
+    /// Character offset of the `$` or quote
+    pub(crate) offset: usize,
+    pub(crate) message: String,
+}
+
+/// A variable a word used without saying what to do when it is unset
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Undefined {
+    /// Character offset of the `$`
+    pub(crate) offset: usize,
+    pub(crate) name: String,
+}
+
+/// The result of expanding a word
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Expanded {
+    pub(crate) value: String,
+    /// Unset variables that were replaced with an empty string
+    pub(crate) undefined: Vec<Undefined>,
+}
+
+/// Expands variables in `word`, looking their values up with `lookup`.
+///
+/// Supported forms, as in Docker: `$VAR`, `${VAR}`, `${VAR:-default}` and
+/// `${VAR-default}`, `${VAR:+alternative}` and `${VAR+alternative}`,
+/// `${VAR:?message}` and `${VAR?message}`, prefix and suffix removal with
+/// `${VAR#pattern}`, `${VAR##pattern}`, `${VAR%pattern}` and
+/// `${VAR%%pattern}`, and replacement with `${VAR/pattern/replacement}` and
+/// `${VAR//pattern/replacement}`. Patterns may use `*` and `?` wildcards.
+/// The forms with a colon also treat an empty value as unset. `escape`
+/// followed by `$` produces a literal `$`.
+pub(crate) fn expand(
+    word: &str,
+    escape: char,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<Expanded, Invalid> {
+    Expander::new(word, escape, false, lookup).run()
+}
+
+/// Expands an ENV, LABEL or ARG value like [`expand`], also removing quotes
+/// the way Docker does: nothing in single quotes is expanded, double quotes
+/// only group, and outside quotes `escape` makes the next character literal.
+pub(crate) fn expand_value(
+    word: &str,
+    escape: char,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<Expanded, Invalid> {
+    Expander::new(word, escape, true, lookup).run()
+}
+
+struct Expander<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    escape: char,
+    /// Whether quotes are processed rather than kept as they are
+    quotes: bool,
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    undefined: Vec<Undefined>,
+}
+
+impl<'a> Expander<'a> {
+    fn new(
+        word: &str,
+        escape: char,
+        quotes: bool,
+        lookup: &'a dyn Fn(&str) -> Option<String>,
+    ) -> Self {
+        Expander {
+            chars: word.chars().collect(),
+            pos: 0,
+            escape,
+            quotes,
+            lookup,
+            undefined: Vec::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<Expanded, Invalid> {
+        let value = self.word(&[])?;
+        Ok(Expanded {
+            value,
+            undefined: self.undefined,
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.pos + ahead).copied()
+    }
+
+    fn invalid(&self, offset: usize, message: String) -> Invalid {
+        Invalid { offset, message }
+    }
+
+    /// Looks up a variable whose value is used as is, noting it when unset
+    fn value(&mut self, offset: usize, name: &str) -> String {
+        (self.lookup)(name).unwrap_or_else(|| {
+            self.undefined.push(Undefined {
+                offset,
+                name: name.to_string(),
+            });
+            String::new()
+        })
+    }
+
+    /// Expands until the end of the input or one of `stops` outside double
+    /// quotes, which is left unconsumed
+    fn word(&mut self, stops: &[char]) -> Result<String, Invalid> {
+        let mut result = String::new();
+        // Where the double quoted text being read started
+        let mut double_quote = None;
+        while let Some(c) = self.peek() {
+            if double_quote.is_none() && stops.contains(&c) {
+                break;
+            }
+            if c == self.escape {
+                let in_quotes = double_quote.is_some();
+                match self.peek_at(1) {
+                    // An escaped `$` or stop character is taken literally
+                    Some(next) if next == '$' || (!in_quotes && stops.contains(&next)) => {
+                        result.push(next);
+                        self.pos += 2;
+                    }
+                    // When removing quotes, an escape outside them makes any
+                    // character literal, and inside them only a quote or escape
+                    Some(next)
+                        if self.quotes && (!in_quotes || next == '"' || next == self.escape) =>
+                    {
+                        result.push(next);
+                        self.pos += 2;
+                    }
+                    // Other escapes are kept for whatever reads the word next
+                    Some(next) => {
+                        result.push(c);
+                        result.push(next);
+                        self.pos += 2;
+                    }
+                    None => {
+                        result.push(c);
+                        self.pos += 1;
+                    }
+                }
+            } else if c == '$' {
+                result.push_str(&self.variable()?);
+            } else if self.quotes && c == '"' {
+                double_quote = match double_quote {
+                    Some(_) => None,
+                    None => Some(self.pos),
+                };
+                self.pos += 1;
+            } else if self.quotes && c == '\'' && double_quote.is_none() {
+                let start = self.pos;
+                self.pos += 1;
+                let Some(length) = self.chars[self.pos..].iter().position(|&c| c == '\'') else {
+                    return Err(self.invalid(start, "unterminated single quote".to_string()));
+                };
+                result.extend(&self.chars[self.pos..self.pos + length]);
+                self.pos += length + 1;
+            } else {
+                result.push(c);
+                self.pos += 1;
+            }
+        }
+        if let Some(start) = double_quote {
+            return Err(self.invalid(start, "unterminated double quote".to_string()));
+        }
+        Ok(result)
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// Expands the variable reference starting at the `$` under the cursor
+    fn variable(&mut self) -> Result<String, Invalid> {
+        let start = self.pos;
+        self.pos += 1;
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                self.braced(start)
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let name = self.name();
+                Ok(self.value(start, &name))
+            }
+            // A `$` that starts no variable is literal
+            _ => Ok("$".to_string()),
+        }
+    }
+
+    /// Expands `${...}` after the opening brace
+    fn braced(&mut self, start: usize) -> Result<String, Invalid> {
+        let name = self.name();
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.invalid(start, "bad substitution: expected a variable name".into()));
+        }
+        let value = (self.lookup)(&name);
+        let colon = self.peek() == Some(':');
+        if colon {
+            self.pos += 1;
+        }
+        let Some(modifier) = self.peek() else {
+            return Err(self.invalid(start, format!("missing '}}' after ${{{}", name)));
+        };
+        self.pos += 1;
+        // With a colon, an empty value counts as unset
+        let is_set = match &value {
+            Some(value) => !(colon && value.is_empty()),
+            None => false,
+        };
+
+        let result = match (modifier, colon) {
+            ('}', false) => return Ok(self.value(start, &name)),
+            ('-', _) => {
+                let word = self.word(&['}'])?;
+                if is_set {
+                    value.unwrap_or_default()
+                } else {
+                    word
+                }
+            }
+            ('+', _) => {
+                let word = self.word(&['}'])?;
+                if is_set {
+                    word
+                } else {
+                    String::new()
+                }
+            }
+            ('?', _) => {
+                let word = self.word(&['}'])?;
+                if !is_set {
+                    let message = if word.is_empty() {
+                        "parameter null or not set".to_string()
+                    } else {
+                        word
+                    };
+                    return Err(self.invalid(start, format!("{}: {}", name, message)));
+                }
+                value.unwrap_or_default()
+            }
+            ('#', false) | ('%', false) => {
+                let longest = self.peek() == Some(modifier);
+                if longest {
+                    self.pos += 1;
+                }
+                let pattern: Vec<char> = self.word(&['}'])?.chars().collect();
+                let value: Vec<char> = self.value(start, &name).chars().collect();
+                if modifier == '#' {
+                    remove_prefix(&value, &pattern, longest)
+                } else {
+                    remove_suffix(&value, &pattern, longest)
+                }
+            }
+            ('/', false) => {
+                let all = self.peek() == Some('/');
+                if all {
+                    self.pos += 1;
+                }
+                let pattern: Vec<char> = self.word(&['/', '}'])?.chars().collect();
+                let replacement = if self.peek() == Some('/') {
+                    self.pos += 1;
+                    self.word(&['}'])?
+                } else {
+                    String::new()
+                };
+                let value: Vec<char> = self.value(start, &name).chars().collect();
+                replace(&value, &pattern, &replacement, all)
+            }
+            (other, _) => {
+                return Err(self.invalid(
+                    start,
+                    format!(
+                        "bad substitution: unsupported modifier '{}{}' in ${{{}}}",
+                        if colon { ":" } else { "" },
+                        other,
+                        name
+                    ),
+                ))
+            }
+        };
+        if self.peek() != Some('}') {
+            return Err(self.invalid(start, format!("missing '}}' after ${{{}", name)));
+        }
+        self.pos += 1;
+        Ok(result)
+    }
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters
+/// and `?` any single character
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| matches(rest, &text[skip..])),
+        Some((&p, rest)) => match text.split_first() {
+            Some((&t, text)) => (p == '?' || p == t) && matches(rest, text),
+            None => false,
+        },
+    }
+}
+
+fn remove_prefix(value: &[char], pattern: &[char], longest: bool) -> String {
+    let mut ends: Vec<usize> = (0..=value.len()).collect();
+    if longest {
+        ends.reverse();
+    }
+    match ends
+        .into_iter()
+        .find(|&end| matches(pattern, &value[..end]))
+    {
+        Some(end) => value[end..].iter().collect(),
+        None => value.iter().collect(),
+    }
+}
+
+fn remove_suffix(value: &[char], pattern: &[char], longest: bool) -> String {
+    let mut starts: Vec<usize> = (0..=value.len()).collect();
+    if !longest {
+        starts.reverse();
+    }
+    match starts
+        .into_iter()
+        .find(|&start| matches(pattern, &value[start..]))
+    {
+        Some(start) => value[..start].iter().collect(),
+        None => value.iter().collect(),
+    }
+}
+
+/// Replaces the first (or every) longest match of `pattern` in `value`
+fn replace(value: &[char], pattern: &[char], replacement: &str, all: bool) -> String {
+    if pattern.is_empty() {
+        return value.iter().collect();
+    }
+    let mut result = String::new();
+    let mut pos = 0;
+    let mut replaced = false;
+    while pos < value.len() {
+        let end = if replaced && !all {
+            None
+        } else {
+            (pos + 1..=value.len())
+                .rev()
+                .find(|&end| matches(pattern, &value[pos..end]))
+        };
+        match end {
+            Some(end) => {
+                result.push_str(replacement);
+                pos = end;
+                replaced = true;
+            }
+            None => {
+                result.push(value[pos]);
+                pos += 1;
+            }
+        }
+    }
+    result
+}
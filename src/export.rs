@@ -0,0 +1,134 @@
+//! Compiles a Dockerfile into files that carry out its steps without dfrun.
+
+mod bash;
+mod github;
+mod make;
+
+use crate::error::Error;
+use crate::expand;
+use crate::parser::{Dockerfile, Instruction, InstructionKind, Stage};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// What [`crate::export`] compiles a Dockerfile into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A standalone bash script
+    Bash,
+    /// A Makefile with a target for each stage
+    Make,
+    /// A justfile with a recipe for each stage
+    Just,
+    /// A GitHub Actions workflow with a step for each instruction
+    GithubActions,
+}
+
+impl ExportFormat {
+    /// Names accepted by `dfrun export --format`
+    pub const NAMES: &'static [&'static str] = &["bash", "make", "just", "github-actions"];
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "bash" => Ok(ExportFormat::Bash),
+            "make" => Ok(ExportFormat::Make),
+            "just" => Ok(ExportFormat::Just),
+            "github-actions" => Ok(ExportFormat::GithubActions),
+            _ => Err(format!("unknown export format: {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportFormat::Bash => write!(f, "bash"),
+            ExportFormat::Make => write!(f, "make"),
+            ExportFormat::Just => write!(f, "just"),
+            ExportFormat::GithubActions => write!(f, "github-actions"),
+        }
+    }
+}
+
+/// Compiles the stages marked in `needed`, along with the instructions before
+/// the first FROM. `path` is the Dockerfile's, for the generated comments.
+pub(crate) fn export(
+    dockerfile: &Dockerfile,
+    stages: &[Stage],
+    needed: &[bool],
+    format: ExportFormat,
+    path: &Path,
+) -> Result<String, Error> {
+    match format {
+        ExportFormat::Bash => bash::script(dockerfile, stages, needed, path),
+        ExportFormat::Make => make::build_file(dockerfile, stages, needed, path, make::Tool::Make),
+        ExportFormat::Just => make::build_file(dockerfile, stages, needed, path, make::Tool::Just),
+        ExportFormat::GithubActions => github::workflow(dockerfile, stages, needed, path),
+    }
+}
+
+/// A variable an ARG becomes
+struct Variable {
+    name: String,
+    /// Default that needs no substitution. Other defaults are applied where
+    /// the ARG declaring them is.
+    default: Option<String>,
+    /// The ARG that declared it first
+    declared_by: String,
+}
+
+/// Collects the ARGs of the preamble and `stages`, in the order they are
+/// first declared
+fn variables(preamble: &[Instruction], stages: &[&Stage], escape: char) -> Vec<Variable> {
+    let mut variables: Vec<Variable> = Vec::new();
+    let instructions = preamble
+        .iter()
+        .chain(stages.iter().flat_map(|stage| stage.instructions.iter()));
+    for instruction in instructions {
+        let InstructionKind::Arg { name, default } = &instruction.kind else {
+            continue;
+        };
+        let default = default
+            .as_deref()
+            .filter(|default| !default.contains('$'))
+            .and_then(|default| expand::expand_value(default, escape, &|_| None).ok())
+            .map(|expanded| expanded.value);
+        match variables.iter_mut().find(|variable| &variable.name == name) {
+            Some(variable) => {
+                variable.default = variable.default.take().or(default);
+            }
+            None => variables.push(Variable {
+                name: name.clone(),
+                default,
+                declared_by: instruction.original.clone(),
+            }),
+        }
+    }
+    variables
+}
+
+/// Names a stage's target after the stage, or after its index when unnamed
+fn target_name(stage: &Stage) -> String {
+    let name = match &stage.name {
+        Some(name) => name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect(),
+        None => stage.index.to_string(),
+    };
+    if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        name
+    } else {
+        format!("stage-{}", name)
+    }
+}
@@ -0,0 +1,755 @@
+//! Compiles a Dockerfile into a standalone bash script.
+//!
+//! Every instruction becomes a block of bash headed by a comment naming its
+//! Dockerfile line. Variables are left for bash to expand when the script
+//! runs, since Docker's substitution syntax is a subset of bash's. Each stage
+//! runs in a subshell so its ENV, ARG and WORKDIR end with it; a stage built
+//! FROM another one starts from the ENV and WORKDIR that stage saved.
+
+use crate::error::Error;
+use crate::git::GitSource;
+use crate::parser::{
+    self, CommandForm, Dockerfile, Heredoc, Instruction, InstructionKind, ParseError, Stage,
+};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Shell functions the script defines when an instruction needs them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Helper {
+    Arg,
+    Copy,
+    Download,
+    Clone,
+    Write,
+    Script,
+    SaveStage,
+}
+
+impl Helper {
+    pub(super) fn source(self) -> &'static str {
+        match self {
+            Helper::Arg => ARG_HELPER,
+            Helper::Copy => COPY_HELPER,
+            Helper::Download => DOWNLOAD_HELPER,
+            Helper::Clone => CLONE_HELPER,
+            Helper::Write => WRITE_HELPER,
+            Helper::Script => SCRIPT_HELPER,
+            Helper::SaveStage => SAVE_STAGE_HELPER,
+        }
+    }
+}
+
+const ARG_HELPER: &str = r#"# Sets an ARG: a value from the environment wins, then one from an earlier
+# ARG when there is no default, then what is typed at a prompt or the default
+dfrun_arg() {
+  local name=$1 value
+  if [[ -n ${!name+x} ]] && { [[ $# -eq 1 ]] || [[ $(declare -p "$name") =~ ^declare\ -[a-zA-Z]*x ]]; }; then
+    return 0
+  fi
+  if [[ -t 0 ]]; then
+    read -r -p "Enter value for ARG $name${2+ (default: $2)}: " value
+    value=${value:-${2-}}
+    if [[ -z $value && $# -eq 1 ]]; then
+      echo "No value provided for ARG $name" >&2
+      exit 1
+    fi
+  elif [[ $# -gt 1 ]]; then
+    value=$2
+  else
+    echo "No value provided for ARG $name (set it in the environment)" >&2
+    exit 1
+  fi
+  printf -v "$name" '%s' "$value"
+}"#;
+
+const COPY_HELPER: &str = r#"# Copies files like COPY: a directory's contents are copied, and a directory
+# destination receives files under their own names. With --extract, tar
+# archives are unpacked into the destination as ADD does.
+dfrun_copy() {
+  local extract= mode= owner= dest source target into_dir=
+  while [[ $1 == --* ]]; do
+    case $1 in
+      --extract) extract=1 ;;
+      --chmod=*) mode=${1#*=} ;;
+      --chown=*) owner=${1#*=} ;;
+    esac
+    shift
+  done
+  dest=${!#}
+  if [[ $# -gt 2 || $dest == */ || $dest == . || $dest == */. || -d $dest ]]; then
+    into_dir=1
+    mkdir -p "$dest"
+  else
+    mkdir -p "$(dirname "$dest")"
+  fi
+  for source in "${@:1:$#-1}"; do
+    if [[ -d $source ]]; then
+      mkdir -p "$dest"
+      cp -R "$source"/. "$dest"
+      target=$dest
+    elif [[ -n $extract ]] && tar -tf "$source" >/dev/null 2>&1; then
+      tar -xf "$source" -C "$dest"
+      continue
+    else
+      target=$dest
+      [[ -z $into_dir ]] || target=${dest%/}/$(basename "$source")
+      cp "$source" "$target"
+    fi
+    [[ -z $mode ]] || chmod -R "$mode" "$target"
+    [[ -z $owner ]] || chown -R "$owner" "$target"
+  done
+}"#;
+
+const DOWNLOAD_HELPER: &str = r#"# Downloads a URL like ADD. A directory destination receives the file under
+# the last segment of the URL's path.
+dfrun_download() {
+  local checksum= mode= owner= url dest path name=
+  while [[ $1 == --* ]]; do
+    case $1 in
+      --checksum=*) checksum=${1#*=} ;;
+      --chmod=*) mode=${1#*=} ;;
+      --chown=*) owner=${1#*=} ;;
+    esac
+    shift
+  done
+  url=$1
+  dest=$2
+  if [[ $dest == */ || $dest == . || $dest == */. || -d $dest ]]; then
+    path=${url#*://}
+    path=${path%%[?#]*}
+    if [[ $path == */* ]]; then
+      path=${path#*/}
+      path=${path%/}
+      name=${path##*/}
+    fi
+    mkdir -p "$dest"
+    dest=${dest%/}/${name:-download}
+  else
+    mkdir -p "$(dirname "$dest")"
+  fi
+  curl -fsSL --retry 3 -o "$dest.dfrun-part" "$url"
+  if [[ -n $checksum ]] && ! echo "${checksum#*:}  $dest.dfrun-part" | "${checksum%%:*}sum" -c --status -; then
+    rm -f "$dest.dfrun-part"
+    echo "Checksum mismatch for $url" >&2
+    return 1
+  fi
+  mv "$dest.dfrun-part" "$dest"
+  [[ -z $mode ]] || chmod "$mode" "$dest"
+  [[ -z $owner ]] || chown "$owner" "$dest"
+}"#;
+
+const CLONE_HELPER: &str = r#"# Clones a git repository into a directory like ADD, at an optional ref and
+# subdirectory
+dfrun_clone() {
+  local keep_git_dir= checkout
+  if [[ $1 == --keep-git-dir ]]; then
+    keep_git_dir=1
+    shift
+  fi
+  checkout=$(mktemp -d)
+  git clone --quiet "$1" "$checkout"
+  [[ -z $2 ]] || git -C "$checkout" checkout --quiet "$2"
+  [[ -n $keep_git_dir ]] || rm -rf "$checkout/.git"
+  mkdir -p "$4"
+  cp -R "$checkout/${3:-.}/." "$4"
+  rm -rf "$checkout"
+}"#;
+
+const WRITE_HELPER: &str = r#"# Writes a COPY heredoc from stdin. A directory destination receives a file
+# named after the heredoc.
+dfrun_write() {
+  local mode= owner= dest
+  while [[ $1 == --* ]]; do
+    case $1 in
+      --chmod=*) mode=${1#*=} ;;
+      --chown=*) owner=${1#*=} ;;
+    esac
+    shift
+  done
+  dest=$2
+  if [[ $dest == */ || $dest == . || $dest == */. || -d $dest ]]; then
+    mkdir -p "$dest"
+    dest=${dest%/}/$1
+  else
+    mkdir -p "$(dirname "$dest")"
+  fi
+  cat > "$dest"
+  [[ -z $mode ]] || chmod "$mode" "$dest"
+  [[ -z $owner ]] || chown "$owner" "$dest"
+}"#;
+
+const SCRIPT_HELPER: &str = r#"# Runs a RUN heredoc from stdin with the interpreter on its #! line
+dfrun_script() {
+  local script status=0
+  script=$(mktemp)
+  cat > "$script"
+  chmod +x "$script"
+  "$script" || status=$?
+  rm -f "$script"
+  return "$status"
+}"#;
+
+const SAVE_STAGE_HELPER: &str = r#"# Saves a stage's ENV and WORKDIR for the stages built FROM it
+dfrun_stages=$(mktemp -d)
+trap 'rm -rf "$dfrun_stages"' EXIT
+dfrun_save_stage() {
+  local file=$dfrun_stages/$1
+  shift
+  {
+    [[ $# -eq 0 ]] || declare -px "$@" 2>/dev/null || true
+    printf 'cd %q\n' "$PWD"
+  } > "$file"
+}"#;
+
+const CONTEXT_SETUP: &str = r#"# COPY and ADD read local files from here
+dfrun_context=$(cd "${DFRUN_CONTEXT:-$(dirname "${BASH_SOURCE[0]}")}" && pwd)"#;
+
+/// Compiles the preamble and the stages marked in `needed` into a script
+pub(super) fn script(
+    dockerfile: &Dockerfile,
+    stages: &[Stage],
+    needed: &[bool],
+    path: &Path,
+) -> Result<String, Error> {
+    let mut script = Script::new(dockerfile.directives.escape, "dfrun_context", false);
+    // Without stages the preamble is the whole build, so its ARGs reach RUN
+    let export_args = stages.is_empty();
+    for instruction in dockerfile.preamble() {
+        script.instruction(instruction, &[], export_args)?;
+    }
+
+    let bases: BTreeSet<usize> = stages
+        .iter()
+        .filter(|stage| needed[stage.index])
+        .filter_map(|stage| stage.base)
+        .collect();
+    // Names set with ENV in each stage and the stages it is built on
+    let mut env_names = vec![BTreeSet::new(); stages.len()];
+    for stage in stages.iter().filter(|stage| needed[stage.index]) {
+        script.comment(stage.from);
+        script.line("(");
+        if let Some(base) = stage.base {
+            script.line(&format!("source \"$dfrun_stages/{}\"", base));
+            env_names[stage.index] = env_names[base].clone();
+        }
+        for instruction in stage.instructions {
+            script.instruction(instruction, &stages[..stage.index], true)?;
+            if let InstructionKind::Env(pairs) = &instruction.kind {
+                env_names[stage.index].extend(pairs.iter().map(|(name, _)| name.clone()));
+            }
+        }
+        if bases.contains(&stage.index) {
+            script.helpers.insert(Helper::SaveStage);
+            script.body.push('\n');
+            let mut save = format!("dfrun_save_stage {}", stage.index);
+            for name in &env_names[stage.index] {
+                save.push(' ');
+                save.push_str(&quote(name));
+            }
+            script.line(&save);
+        }
+        if script.body.ends_with("(\n") {
+            script.line(":");
+        }
+        script.line(")");
+    }
+
+    let mut output = format!(
+        "#!/usr/bin/env bash\n# Generated by dfrun from {}. ARG values can be set in the environment.\nset -euo pipefail\n",
+        path.display()
+    );
+    for helper in &script.helpers {
+        output.push('\n');
+        output.push_str(helper.source());
+        output.push('\n');
+    }
+    if script.uses_context {
+        output.push('\n');
+        output.push_str(CONTEXT_SETUP);
+        output.push('\n');
+    }
+    output.push('\n');
+    output.push_str(&script.body);
+    Ok(output)
+}
+
+/// Bash for a run of instructions, along with what it relies on
+pub(super) struct Script {
+    escape: char,
+    /// Variable holding the build context's path
+    context: &'static str,
+    /// Whether ARGs are variables set before the script runs, which it only
+    /// checks or defaults, rather than prompted for
+    arg_variables: bool,
+    pub(super) body: String,
+    pub(super) helpers: BTreeSet<Helper>,
+    /// Whether any instruction reads from the build context
+    pub(super) uses_context: bool,
+}
+
+impl Script {
+    pub(super) fn new(escape: char, context: &'static str, arg_variables: bool) -> Self {
+        Script {
+            escape,
+            context,
+            arg_variables,
+            body: String::new(),
+            helpers: BTreeSet::new(),
+            uses_context: false,
+        }
+    }
+
+    pub(super) fn line(&mut self, line: &str) {
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    /// Starts a block with a comment naming the instruction's lines
+    pub(super) fn comment(&mut self, instruction: &Instruction) {
+        if !self.body.is_empty() && !self.body.ends_with("(\n") {
+            self.body.push('\n');
+        }
+        self.line(&format!(
+            "# {}: {}",
+            instruction.line_range(),
+            instruction.original
+        ));
+    }
+
+    /// A word for bash that expands to what Docker would substitute
+    fn word(&self, raw: &str) -> String {
+        shell_word(raw, self.escape, false, false)
+    }
+
+    /// Like [`Script::word`], for an ENV, LABEL or ARG value with quotes
+    fn value(&self, raw: &str) -> String {
+        shell_word(raw, self.escape, true, false)
+    }
+
+    /// Like [`Script::word`], leaving `*` and `?` for bash to match files with
+    fn pattern(&self, raw: &str) -> String {
+        shell_word(raw, self.escape, false, true)
+    }
+
+    /// The `--chmod` and `--chown` options of COPY and ADD for a helper
+    fn copy_flags(&self, instruction: &Instruction) -> String {
+        let mut flags = String::new();
+        for name in ["chmod", "chown"] {
+            if let Some(value) = instruction.flag(name) {
+                flags.push_str(&format!(" --{}={}", name, self.word(value)));
+            }
+        }
+        flags
+    }
+
+    pub(super) fn instruction(
+        &mut self,
+        instruction: &Instruction,
+        earlier_stages: &[Stage],
+        export_args: bool,
+    ) -> Result<(), Error> {
+        self.comment(instruction);
+        match &instruction.kind {
+            InstructionKind::Workdir(dir) => {
+                let dir = self.word(dir);
+                self.line(&format!("mkdir -p {}", dir));
+                self.line(&format!("cd {}", dir));
+            }
+            InstructionKind::Run(form) => self.run(form, instruction),
+            InstructionKind::Env(pairs) => {
+                for (name, value) in pairs {
+                    let line = format!("export {}={}", name, self.value(value));
+                    self.line(&line);
+                }
+            }
+            InstructionKind::Arg { name, default } if self.arg_variables => {
+                let line = match default {
+                    // Defaults that use variables are only known when the script runs
+                    Some(default) if default.contains('$') => {
+                        format!("export {}=\"${{{}:-{}}}\"", name, name, self.value(default))
+                    }
+                    Some(_) => format!("# Set by the {} variable", name),
+                    None => format!(": \"${{{}:?No value provided for ARG {}}}\"", name, name),
+                };
+                self.line(&line);
+            }
+            InstructionKind::Arg { name, default } => {
+                self.helpers.insert(Helper::Arg);
+                let line = match default {
+                    Some(default) => format!("dfrun_arg {} {}", name, self.value(default)),
+                    None => format!("dfrun_arg {}", name),
+                };
+                self.line(&line);
+                if export_args {
+                    self.line(&format!("export {}", name));
+                }
+            }
+            InstructionKind::Copy { sources, dest } => {
+                let from = match instruction.flag("from") {
+                    Some(reference) => {
+                        if parser::find_stage(earlier_stages, reference).is_none() {
+                            return Err(unsupported(
+                                instruction,
+                                format!(
+                                    "COPY --from={} does not name an earlier build stage; dfrun cannot copy from images",
+                                    reference
+                                ),
+                            ));
+                        }
+                        true
+                    }
+                    None => false,
+                };
+                let flags = self.copy_flags(instruction);
+                let dest = self.word(dest);
+                let mut local_sources = Vec::new();
+                for source in sources {
+                    match instruction.heredoc_source(source) {
+                        Some(heredoc) => self.heredoc_file(heredoc, &flags, &dest),
+                        // Stages share the host's filesystem, so their paths are the host's
+                        None if from => local_sources
+                            .push(format!("/{}", self.pattern(source.trim_start_matches('/')))),
+                        None => local_sources.push(self.context_path(source)),
+                    }
+                }
+                if !local_sources.is_empty() {
+                    self.helpers.insert(Helper::Copy);
+                    let line = format!("dfrun_copy{} {} {}", flags, local_sources.join(" "), dest);
+                    self.line(&line);
+                }
+            }
+            InstructionKind::Add { sources, dest } => self.add(instruction, sources, dest),
+            InstructionKind::From { .. }
+            | InstructionKind::Cmd(_)
+            | InstructionKind::Entrypoint(_)
+            | InstructionKind::Label(_)
+            | InstructionKind::Other { .. } => {
+                self.line("# Nothing to run");
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a command in a subshell, so that `cd` and `exit` stay in the step
+    /// as they do in Docker, unless each step already has a shell of its own
+    fn run(&mut self, form: &CommandForm, instruction: &Instruction) {
+        let heredocs = &instruction.heredocs;
+        match form {
+            CommandForm::Shell(command) if !heredocs.is_empty() => {
+                if heredocs.len() == 1 && parser::parse_heredoc_marker(command.trim()).is_some() {
+                    let heredoc = &heredocs[0];
+                    if heredoc.content.starts_with("#!") {
+                        self.helpers.insert(Helper::Script);
+                        self.line(&format!("dfrun_script <<'{}'", heredoc.name));
+                        self.body.push_str(&heredoc.content);
+                        self.line(&heredoc.name);
+                    } else {
+                        self.shell(&heredoc.content);
+                    }
+                } else {
+                    let mut script = format!("{}\n", command);
+                    for heredoc in heredocs {
+                        script.push_str(&heredoc.content);
+                        script.push_str(&heredoc.name);
+                        script.push('\n');
+                    }
+                    self.shell(&script);
+                }
+            }
+            CommandForm::Shell(command) => self.shell(command),
+            CommandForm::Exec(argv) => {
+                let argv: Vec<String> = argv.iter().map(|arg| quote(arg)).collect();
+                self.line(&argv.join(" "));
+            }
+        }
+    }
+
+    /// Runs a shell form command with `bash -c`, as dfrun does, so that it
+    /// neither sees the script's `set -euo pipefail` nor changes its state
+    fn shell(&mut self, command: &str) {
+        self.line(&format!("bash -c {}", quote(command)));
+    }
+
+    fn add(&mut self, instruction: &Instruction, sources: &[String], dest: &str) {
+        let flags = self.copy_flags(instruction);
+        let dest = self.word(dest);
+        let mut local_sources = Vec::new();
+        for source in sources {
+            if let Some(heredoc) = instruction.heredoc_source(source) {
+                self.heredoc_file(heredoc, &flags, &dest);
+            } else if let Some(repository) = GitSource::parse(source) {
+                self.helpers.insert(Helper::Clone);
+                let keep_git_dir = match instruction.flag("keep-git-dir") {
+                    Some("" | "true") => " --keep-git-dir",
+                    _ => "",
+                };
+                let line = format!(
+                    "dfrun_clone{} {} {} {} {}",
+                    keep_git_dir,
+                    self.word(&repository.url),
+                    self.word(repository.reference.as_deref().unwrap_or("")),
+                    self.word(repository.subdir.as_deref().unwrap_or("")),
+                    dest
+                );
+                self.line(&line);
+            } else if source.starts_with("http://") || source.starts_with("https://") {
+                self.helpers.insert(Helper::Download);
+                let mut line = "dfrun_download".to_string();
+                if let Some(checksum) = instruction.flag("checksum") {
+                    line.push_str(&format!(" --checksum={}", self.word(checksum)));
+                }
+                line.push_str(&format!("{} {} {}", flags, self.word(source), dest));
+                self.line(&line);
+            } else {
+                local_sources.push(self.context_path(source));
+            }
+        }
+        if !local_sources.is_empty() {
+            self.helpers.insert(Helper::Copy);
+            let line = format!(
+                "dfrun_copy --extract{} {} {}",
+                flags,
+                local_sources.join(" "),
+                dest
+            );
+            self.line(&line);
+        }
+    }
+
+    /// Writes a COPY or ADD heredoc source with the same delimiter, so bash
+    /// expands variables in it unless the Dockerfile quoted the delimiter
+    fn heredoc_file(&mut self, heredoc: &Heredoc, flags: &str, dest: &str) {
+        self.helpers.insert(Helper::Write);
+        let delimiter = if heredoc.expand {
+            heredoc.name.clone()
+        } else {
+            format!("'{}'", heredoc.name)
+        };
+        self.line(&format!(
+            "dfrun_write{} {} {} <<{}",
+            flags,
+            quote(&heredoc.name),
+            dest,
+            delimiter
+        ));
+        self.body.push_str(&heredoc.content);
+        self.line(&heredoc.name);
+    }
+
+    /// A path in the build context
+    fn context_path(&mut self, source: &str) -> String {
+        self.uses_context = true;
+        let source = source.trim_start_matches('/');
+        format!("\"${}\"/{}", self.context, self.pattern(source))
+    }
+}
+
+fn unsupported(instruction: &Instruction, message: String) -> Error {
+    Error::Export(ParseError {
+        line: instruction.start_line,
+        message,
+    })
+}
+
+/// Whether a character needs no quoting in a bash word
+fn is_plain(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_./:=@%+,-".contains(c)
+}
+
+/// Quotes a literal string for bash, leaving plain words as they are
+pub(super) fn quote(s: &str) -> String {
+    if !s.is_empty() && s.chars().all(is_plain) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Converts an instruction argument into a bash word that expands to what
+/// Docker would substitute: variable references are passed to bash (see
+/// [`bash_reference`]) and everything else is quoted. With `quotes`, quotes and escapes
+/// are removed the way Docker does for ENV, LABEL and ARG values. With
+/// `globs`, `*` and `?` are left unquoted.
+pub(super) fn shell_word(raw: &str, escape: char, quotes: bool, globs: bool) -> String {
+    let chars: Vec<char> = raw.chars().collect();
+    let mut word = Word::default();
+    let mut in_double_quotes = false;
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c == escape && pos + 1 < chars.len() {
+            let next = chars[pos + 1];
+            let removes_escape =
+                next == '$' || (quotes && (!in_double_quotes || next == '"' || next == escape));
+            if !removes_escape {
+                word.literal(c, false);
+            }
+            word.literal(next, false);
+            pos += 2;
+        } else if c == '$' {
+            let end = variable_end(&chars, pos);
+            if end == pos + 1 {
+                word.literal('$', false);
+            } else {
+                word.quoted(&bash_reference(&chars[pos..end]));
+            }
+            pos = end;
+        } else if quotes && c == '"' {
+            in_double_quotes = !in_double_quotes;
+            pos += 1;
+        } else if quotes && c == '\'' && !in_double_quotes {
+            let end = chars[pos + 1..]
+                .iter()
+                .position(|&c| c == '\'')
+                .map_or(chars.len(), |length| pos + 1 + length);
+            for &c in &chars[pos + 1..end] {
+                word.literal(c, false);
+            }
+            pos = end + 1;
+        } else {
+            word.literal(c, globs && !in_double_quotes);
+            pos += 1;
+        }
+    }
+    word.finish()
+}
+
+/// Rewrites a Docker variable reference for a script running under `set -u`.
+/// Docker substitutes an empty string for an unset variable, so a plain
+/// `$NAME` becomes `${NAME-}`, and `#`, `%` and `/` modifiers only apply
+/// when the variable is set. Only `${NAME:?}` and `${NAME?}` stop the script.
+fn bash_reference(reference: &[char]) -> String {
+    if reference.get(1) != Some(&'{') {
+        let name: String = reference[1..].iter().collect();
+        return format!("${{{}-}}", name);
+    }
+    if reference.last() != Some(&'}') {
+        // Unterminated, which Docker rejects as well
+        return reference.iter().collect();
+    }
+    let end = reference[2..]
+        .iter()
+        .position(|&c| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(reference.len(), |length| 2 + length);
+    let name: String = reference[2..end].iter().collect();
+    let modifier = &reference[end..reference.len() - 1];
+    match modifier.first() {
+        None => format!("${{{}-}}", name),
+        Some('#' | '%' | '/') => format!(
+            "${{{name}+${{{name}{}}}}}",
+            bash_references(modifier),
+            name = name
+        ),
+        Some(_) => format!("${{{}{}}}", name, bash_references(modifier)),
+    }
+}
+
+/// Rewrites the variable references in a modifier's word, such as the `$B`
+/// in `${A:-$B}`, with [`bash_reference`]
+fn bash_references(text: &[char]) -> String {
+    let mut result = String::new();
+    let mut pos = 0;
+    while pos < text.len() {
+        let end = if text[pos] == '$' {
+            variable_end(text, pos)
+        } else {
+            pos + 1
+        };
+        if end > pos + 1 {
+            result.push_str(&bash_reference(&text[pos..end]));
+        } else {
+            result.extend(&text[pos..end]);
+        }
+        pos = end;
+    }
+    result
+}
+
+/// Where the variable reference starting with the `$` at `start` ends, or
+/// just after the `$` when it starts none
+fn variable_end(chars: &[char], start: usize) -> usize {
+    match chars.get(start + 1) {
+        Some('{') => {
+            let mut depth = 0;
+            for (pos, &c) in chars.iter().enumerate().skip(start + 1) {
+                match c {
+                    '{' => depth += 1,
+                    '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return pos + 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            chars.len()
+        }
+        Some(&c) if c.is_ascii_alphabetic() || c == '_' => chars[start + 1..]
+            .iter()
+            .position(|&c| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(chars.len(), |length| start + 1 + length),
+        _ => start + 1,
+    }
+}
+
+/// A bash word built up from double quoted text and unquoted wildcards
+#[derive(Default)]
+struct Word {
+    text: String,
+    in_quotes: bool,
+    /// Whether the word needs quotes at all
+    plain: bool,
+    started: bool,
+}
+
+impl Word {
+    fn open(&mut self) {
+        if !self.in_quotes {
+            self.text.push('"');
+            self.in_quotes = true;
+        }
+    }
+
+    fn literal(&mut self, c: char, wildcard: bool) {
+        self.note(is_plain(c) || (wildcard && (c == '*' || c == '?')));
+        if wildcard && (c == '*' || c == '?') {
+            if self.in_quotes {
+                self.text.push('"');
+                self.in_quotes = false;
+            }
+            self.text.push(c);
+            return;
+        }
+        self.open();
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            self.text.push('\\');
+        }
+        self.text.push(c);
+    }
+
+    fn quoted(&mut self, text: &str) {
+        self.note(false);
+        self.open();
+        self.text.push_str(text);
+    }
+
+    fn note(&mut self, plain: bool) {
+        self.plain = plain && (self.plain || !self.started);
+        self.started = true;
+    }
+
+    fn finish(mut self) -> String {
+        if !self.started {
+            return "''".to_string();
+        }
+        if self.plain {
+            return self.text.replace('"', "");
+        }
+        if self.in_quotes {
+            self.text.push('"');
+        }
+        self.text
+    }
+}
@@ -0,0 +1,506 @@
+//! Compiles a Dockerfile into a GitHub Actions workflow.
+//!
+//! The target stage and the stages it needs run as one job, since they share
+//! the runner's filesystem. RUN, WORKDIR, COPY and ADD become steps. ARGs
+//! become workflow inputs, and each step's `env:` holds the ARGs and ENV in
+//! scope, substituted into expressions. What expressions cannot express, such
+//! as host variables or `${VAR:-default}`, is left for bash at the start of
+//! the step's script.
+
+use super::bash::{shell_word, Script};
+use super::{target_name, variables};
+use crate::error::Error;
+use crate::expand;
+use crate::parser::{Dockerfile, Instruction, InstructionKind, Stage};
+use std::path::Path;
+
+/// What a variable is set to in a step
+#[derive(Debug, Clone)]
+enum Value {
+    /// Text for `env:`, which may contain `${{ }}` expressions
+    Expression(String),
+    /// A bash word the step's script assigns
+    Shell(String),
+    /// The workflow input an ARG becomes, with the bash word it defaults to
+    /// when its default needs substitution
+    Input { default: Option<String> },
+}
+
+#[derive(Debug, Clone)]
+struct Variable {
+    name: String,
+    value: Value,
+    /// Set by ARG rather than ENV
+    arg: bool,
+}
+
+/// Where a stage's steps run
+#[derive(Debug, Clone, Default)]
+enum Workdir {
+    /// The workspace, where a job starts
+    #[default]
+    Start,
+    /// A path for `working-directory:`
+    Path(String),
+    /// A bash word for a directory to `cd` into from `base`
+    Shell { base: Option<String>, word: String },
+}
+
+/// The ENV, ARG and WORKDIR a step runs with
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    variables: Vec<Variable>,
+    workdir: Workdir,
+}
+
+struct Step {
+    /// The FROM instruction, for the first step of a stage
+    from: Option<String>,
+    name: String,
+    working_directory: Option<String>,
+    env: Vec<(String, String)>,
+    run: String,
+}
+
+/// Compiles the stages marked in `needed` into a workflow with one job
+pub(super) fn workflow(
+    dockerfile: &Dockerfile,
+    stages: &[Stage],
+    needed: &[bool],
+    path: &Path,
+) -> Result<String, Error> {
+    let escape = dockerfile.directives.escape;
+    let preamble = dockerfile.preamble();
+    let needed_stages: Vec<&Stage> = stages.iter().filter(|stage| needed[stage.index]).collect();
+    let inputs = variables(preamble, &needed_stages, escape);
+    // Inputs no ARG gives a default are required
+    let instructions: Vec<&Instruction> = preamble
+        .iter()
+        .chain(needed_stages.iter().flat_map(|stage| stage.instructions))
+        .collect();
+    let has_default = |name: &str| {
+        instructions.iter().any(|instruction| {
+            matches!(&instruction.kind, InstructionKind::Arg { name: arg, default: Some(_) } if arg == name)
+        })
+    };
+
+    let mut job = Job {
+        escape,
+        steps: Vec::new(),
+        uses_context: false,
+        globals: Vec::new(),
+    };
+    if stages.is_empty() {
+        let mut scope = Scope::default();
+        for instruction in preamble {
+            job.instruction(&mut scope, instruction, &[])?;
+        }
+    }
+    job.globals = preamble
+        .iter()
+        .filter_map(|instruction| match &instruction.kind {
+            InstructionKind::Arg {
+                name,
+                default: Some(default),
+            } if default.contains('$') => Some((name.clone(), default.clone())),
+            _ => None,
+        })
+        .collect();
+    // Scopes of finished stages, for the stages built FROM them
+    let mut finished: Vec<Option<Scope>> = vec![None; stages.len()];
+    for stage in &needed_stages {
+        let mut scope = match stage.base.and_then(|base| finished[base].clone()) {
+            Some(mut scope) => {
+                scope.variables.retain(|variable| !variable.arg);
+                scope
+            }
+            None => Scope::default(),
+        };
+        let first_step = job.steps.len();
+        for instruction in stage.instructions {
+            job.instruction(&mut scope, instruction, &stages[..stage.index])?;
+        }
+        if let Some(step) = job.steps.get_mut(first_step) {
+            step.from = Some(format!(
+                "{}: {}",
+                stage.from.line_range(),
+                stage.from.original
+            ));
+        }
+        finished[stage.index] = Some(scope);
+    }
+
+    let mut output = format!(
+        "# Generated by dfrun from {}. The build runs as one job with a step for each\n\
+         # RUN, WORKDIR, COPY and ADD; ARGs are inputs.\n\
+         name: {}\n\
+         on:\n",
+        path.display(),
+        yaml_string(&path.display().to_string())
+    );
+    for trigger in ["workflow_dispatch", "workflow_call"] {
+        output.push_str(&format!("  {}:\n", trigger));
+        if inputs.is_empty() {
+            continue;
+        }
+        output.push_str("    inputs:\n");
+        for input in &inputs {
+            output.push_str(&format!(
+                "      {}:\n        description: {}\n        required: {}\n        type: string\n",
+                input.name,
+                yaml_string(&literal(&input.declared_by)),
+                !has_default(&input.name)
+            ));
+            if let Some(default) = &input.default {
+                output.push_str(&format!(
+                    "        default: {}\n",
+                    yaml_string(&literal(default))
+                ));
+            }
+        }
+    }
+    let job_name = needed_stages
+        .last()
+        .map_or_else(|| "build".to_string(), |stage| target_name(stage));
+    output.push_str(&format!(
+        "jobs:\n  {}:\n    runs-on: ubuntu-latest\n    defaults:\n      run:\n        shell: bash\n    steps:\n",
+        job_name
+    ));
+    if job.uses_context {
+        output.push_str("      - uses: actions/checkout@v4\n");
+    }
+    if job.steps.is_empty() {
+        output.push_str("      - name: Nothing to run\n        run: \"true\"\n");
+    }
+    for step in &job.steps {
+        if let Some(from) = &step.from {
+            output.push_str(&format!("      # {}\n", from));
+        }
+        output.push_str(&format!(
+            "      - name: {}\n",
+            yaml_string(&literal(&step.name))
+        ));
+        if let Some(dir) = &step.working_directory {
+            output.push_str(&format!(
+                "        working-directory: {}\n",
+                yaml_string(dir)
+            ));
+        }
+        if !step.env.is_empty() {
+            output.push_str("        env:\n");
+            for (name, value) in &step.env {
+                output.push_str(&format!("          {}: {}\n", name, yaml_string(value)));
+            }
+        }
+        output.push_str("        run: |\n");
+        for line in literal(&step.run).lines() {
+            if !line.is_empty() {
+                output.push_str("          ");
+                output.push_str(line);
+            }
+            output.push('\n');
+        }
+    }
+    Ok(output)
+}
+
+struct Job {
+    escape: char,
+    steps: Vec<Step>,
+    /// Whether any step reads from the build context, which is the checkout
+    uses_context: bool,
+    /// Defaults of the ARGs before the first FROM that need substitution
+    globals: Vec<(String, String)>,
+}
+
+impl Job {
+    fn instruction(
+        &mut self,
+        scope: &mut Scope,
+        instruction: &Instruction,
+        earlier_stages: &[Stage],
+    ) -> Result<(), Error> {
+        let name = format!("{}: {}", instruction.line_range(), instruction.original);
+        match &instruction.kind {
+            InstructionKind::Env(pairs) => {
+                for (name, raw) in pairs {
+                    let value = self.value(scope, raw);
+                    set(scope, name.clone(), value, false);
+                }
+            }
+            InstructionKind::Arg { name, default } => {
+                // ENV wins over an ARG with the same name
+                if !scope
+                    .variables
+                    .iter()
+                    .any(|variable| &variable.name == name && !variable.arg)
+                {
+                    // A stage's ARG without a default takes the one before the first FROM
+                    let global = || {
+                        self.globals
+                            .iter()
+                            .find(|(global, _)| global == name)
+                            .map(|(_, default)| default.as_str())
+                    };
+                    let default = default
+                        .as_deref()
+                        .or_else(global)
+                        .filter(|default| default.contains('$'))
+                        .map(|default| shell_word(default, self.escape, true, false));
+                    set(scope, name.clone(), Value::Input { default }, true);
+                }
+            }
+            InstructionKind::Workdir(raw) => {
+                let workdir = match self.expression(scope, raw, false) {
+                    Some(dir) if dir.starts_with('/') => Workdir::Path(dir),
+                    Some(dir) => match &scope.workdir {
+                        Workdir::Start => Workdir::Path(dir),
+                        Workdir::Path(current) => {
+                            Workdir::Path(format!("{}/{}", current.trim_end_matches('/'), dir))
+                        }
+                        Workdir::Shell { base, word } => Workdir::Shell {
+                            base: base.clone(),
+                            word: format!(
+                                "{}/{}",
+                                word,
+                                shell_word(raw, self.escape, false, false)
+                            ),
+                        },
+                    },
+                    None => {
+                        let dir = shell_word(raw, self.escape, false, false);
+                        match &scope.workdir {
+                            _ if raw.starts_with('/') => Workdir::Shell {
+                                base: None,
+                                word: dir,
+                            },
+                            Workdir::Start => Workdir::Shell {
+                                base: None,
+                                word: dir,
+                            },
+                            Workdir::Path(current) => Workdir::Shell {
+                                base: Some(current.clone()),
+                                word: dir,
+                            },
+                            Workdir::Shell { base, word } => Workdir::Shell {
+                                base: base.clone(),
+                                word: format!("{}/{}", word, dir),
+                            },
+                        }
+                    }
+                };
+                // The directory has to exist before a step can run in it
+                let step = match &workdir {
+                    Workdir::Path(dir) => Step {
+                        from: None,
+                        name,
+                        working_directory: None,
+                        env: vec![("DFRUN_WORKDIR".to_string(), dir.clone())],
+                        run: "mkdir -p \"$DFRUN_WORKDIR\"\n".to_string(),
+                    },
+                    Workdir::Shell { base, word } => Step {
+                        from: None,
+                        name,
+                        working_directory: base.clone(),
+                        env: env(scope),
+                        run: format!("{}mkdir -p {}\n", prelude(scope, None), word),
+                    },
+                    Workdir::Start => unreachable!("WORKDIR always names a directory"),
+                };
+                self.steps.push(step);
+                scope.workdir = workdir;
+            }
+            InstructionKind::Run(_)
+            | InstructionKind::Copy { .. }
+            | InstructionKind::Add { .. } => {
+                let mut script = Script::new(self.escape, "GITHUB_WORKSPACE", true);
+                script.instruction(instruction, earlier_stages, true)?;
+                self.uses_context |= script.uses_context;
+                let mut run = String::new();
+                for helper in &script.helpers {
+                    run.push_str(helper.source());
+                    run.push('\n');
+                }
+                let cd = match &scope.workdir {
+                    Workdir::Shell { word, .. } => Some(word.as_str()),
+                    _ => None,
+                };
+                run.push_str(&prelude(scope, cd));
+                // The step's name already says which instruction it is
+                let body = script.body.split_once('\n').map_or("", |(_, body)| body);
+                run.push_str(body);
+                self.steps.push(Step {
+                    from: None,
+                    name,
+                    working_directory: match &scope.workdir {
+                        Workdir::Start => None,
+                        Workdir::Path(dir) => Some(dir.clone()),
+                        Workdir::Shell { base, .. } => base.clone(),
+                    },
+                    env: env(scope),
+                    run,
+                });
+            }
+            InstructionKind::From { .. }
+            | InstructionKind::Cmd(_)
+            | InstructionKind::Entrypoint(_)
+            | InstructionKind::Label(_)
+            | InstructionKind::Other { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// An ENV value as an expression where possible, or else as bash
+    fn value(&self, scope: &Scope, raw: &str) -> Value {
+        match self.expression(scope, raw, true) {
+            Some(expression) => Value::Expression(expression),
+            None => Value::Shell(shell_word(raw, self.escape, true, false)),
+        }
+    }
+
+    /// Substitutes variables into an expression for GitHub, or returns none
+    /// when `raw` uses a variable only bash can supply or a modifier
+    fn expression(&self, scope: &Scope, raw: &str, quotes: bool) -> Option<String> {
+        let lookup = |name: &str| {
+            let variable = scope
+                .variables
+                .iter()
+                .find(|variable| variable.name == name)?;
+            match &variable.value {
+                Value::Expression(expression) => Some(expression.clone()),
+                Value::Input { default: None } => Some(format!("${{{{ inputs.{} }}}}", name)),
+                _ => None,
+            }
+        };
+        for name in plain_references(raw, self.escape)? {
+            lookup(&name)?;
+        }
+        let expanded = if quotes {
+            expand::expand_value(raw, self.escape, &lookup)
+        } else {
+            expand::expand(raw, self.escape, &lookup)
+        };
+        expanded.ok().map(|expanded| expanded.value)
+    }
+}
+
+/// Sets a variable, moving it after the variables its value may refer to
+fn set(scope: &mut Scope, name: String, value: Value, arg: bool) {
+    scope.variables.retain(|variable| variable.name != name);
+    scope.variables.push(Variable { name, value, arg });
+}
+
+/// The `env:` of a step
+fn env(scope: &Scope) -> Vec<(String, String)> {
+    scope
+        .variables
+        .iter()
+        .filter_map(|variable| match &variable.value {
+            Value::Expression(expression) => Some((variable.name.clone(), expression.clone())),
+            Value::Input { .. } => Some((
+                variable.name.clone(),
+                format!("${{{{ inputs.{} }}}}", variable.name),
+            )),
+            Value::Shell(_) => None,
+        })
+        .collect()
+}
+
+/// Bash that sets what `env:` cannot, then enters `cd`
+fn prelude(scope: &Scope, cd: Option<&str>) -> String {
+    let mut prelude = String::new();
+    for variable in &scope.variables {
+        match &variable.value {
+            Value::Shell(word) => {
+                prelude.push_str(&format!("export {}={}\n", variable.name, word));
+            }
+            Value::Input {
+                default: Some(word),
+            } => {
+                prelude.push_str(&format!(
+                    "export {}=\"${{{}:-{}}}\"\n",
+                    variable.name, variable.name, word
+                ));
+            }
+            _ => {}
+        }
+    }
+    if let Some(dir) = cd {
+        prelude.push_str(&format!("cd {}\n", dir));
+    }
+    prelude
+}
+
+/// Names of the variables `raw` refers to, or none when a reference uses a
+/// modifier such as `${VAR:-default}`
+fn plain_references(raw: &str, escape: char) -> Option<Vec<String>> {
+    let chars: Vec<char> = raw.chars().collect();
+    let mut names = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        if chars[pos] == escape {
+            pos += 2;
+            continue;
+        }
+        if chars[pos] != '$' {
+            pos += 1;
+            continue;
+        }
+        pos += 1;
+        let braced = chars.get(pos) == Some(&'{');
+        if braced {
+            pos += 1;
+        }
+        let start = pos;
+        while chars
+            .get(pos)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            pos += 1;
+        }
+        let name: String = chars[start..pos].iter().collect();
+        if braced {
+            if name.is_empty() || chars.get(pos) != Some(&'}') {
+                return None;
+            }
+            pos += 1;
+        }
+        if !name.is_empty() {
+            names.push(name);
+        }
+    }
+    Some(names)
+}
+
+/// Keeps GitHub from evaluating `${{` in text as an expression
+fn literal(text: &str) -> String {
+    text.replace("${{", "${{ '${{' }}")
+}
+
+/// Writes a YAML scalar, quoting it unless it is a plain word YAML reads as
+/// a string
+fn yaml_string(value: &str) -> String {
+    let plain = value.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '/')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_./-".contains(c))
+        && !matches!(
+            value.to_ascii_lowercase().as_str(),
+            "true" | "false" | "yes" | "no" | "on" | "off" | "null" | "y" | "n"
+        );
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
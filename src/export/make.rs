@@ -0,0 +1,307 @@
+//! Compiles a Dockerfile into a Makefile or justfile.
+//!
+//! Each build stage becomes a target that depends on the stages it builds on
+//! or copies from, and whose recipe is the stage's bash. ARGs become
+//! variables that can be overridden on the command line, which a recipe
+//! unsets when its stage does not declare them. Recipes run in
+//! shells of their own, so a stage built FROM another one repeats that
+//! stage's ARG, ENV and WORKDIR instructions to start where it left off.
+
+use super::bash::Script;
+use super::{target_name, variables, Variable};
+use crate::error::Error;
+use crate::parser::{Dockerfile, Instruction, InstructionKind, Stage};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Which tool the recipes are written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Tool {
+    Make,
+    Just,
+}
+
+/// A target a stage becomes
+struct Target {
+    name: String,
+    dependencies: Vec<String>,
+    /// The FROM instruction, or none for a Dockerfile without stages
+    from: Option<String>,
+    script: Script,
+}
+
+/// Compiles the stages marked in `needed` into a Makefile or justfile
+pub(super) fn build_file(
+    dockerfile: &Dockerfile,
+    stages: &[Stage],
+    needed: &[bool],
+    path: &Path,
+    tool: Tool,
+) -> Result<String, Error> {
+    let escape = dockerfile.directives.escape;
+    let preamble = dockerfile.preamble();
+    let needed_stages: Vec<&Stage> = stages.iter().filter(|stage| needed[stage.index]).collect();
+
+    let variables = variables(preamble, &needed_stages, escape);
+
+    let mut targets = Vec::new();
+    if stages.is_empty() {
+        let mut script = Script::new(escape, "DFRUN_CONTEXT", true);
+        for instruction in preamble {
+            script.instruction(instruction, &[], true)?;
+        }
+        targets.push(Target {
+            name: "build".to_string(),
+            dependencies: Vec::new(),
+            from: None,
+            script,
+        });
+    }
+    for stage in &needed_stages {
+        let mut script = Script::new(escape, "DFRUN_CONTEXT", true);
+        // Defaults that are only known when the recipe runs
+        for instruction in preamble.iter().filter(|instruction| {
+            matches!(&instruction.kind, InstructionKind::Arg { default: Some(default), .. } if default.contains('$'))
+        }) {
+            script.instruction(instruction, &[], true)?;
+        }
+        let mut bases = Vec::new();
+        let mut base = stage.base;
+        while let Some(index) = base {
+            bases.push(&stages[index]);
+            base = stages[index].base;
+        }
+        // Every ARG is a variable of the whole file, but as in Docker a
+        // stage only sees those it or the stages it builds on declare
+        let declared: BTreeSet<&str> = bases
+            .iter()
+            .copied()
+            .chain([*stage])
+            .flat_map(|stage| stage.instructions)
+            .filter_map(|instruction| match &instruction.kind {
+                InstructionKind::Arg { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let undeclared: Vec<&str> = variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .filter(|name| !declared.contains(name))
+            .collect();
+        if !undeclared.is_empty() {
+            if !script.body.is_empty() {
+                script.line("");
+            }
+            script.line("# ARGs this stage does not declare");
+            script.line(&format!("unset {}", undeclared.join(" ")));
+        }
+        for base in bases.iter().rev() {
+            for instruction in base
+                .instructions
+                .iter()
+                .filter(|instruction| sets_up(instruction))
+            {
+                script.instruction(instruction, &stages[..base.index], true)?;
+            }
+        }
+        for instruction in stage.instructions {
+            script.instruction(instruction, &stages[..stage.index], true)?;
+        }
+        targets.push(Target {
+            name: target_name(stage),
+            dependencies: stage
+                .dependencies(stages)
+                .into_iter()
+                .map(|index| target_name(&stages[index]))
+                .collect(),
+            from: Some(format!(
+                "{}: {}",
+                stage.from.line_range(),
+                stage.from.original
+            )),
+            script,
+        });
+    }
+
+    let default_target = targets.last().map(|target| target.name.clone());
+    let mut helpers = String::new();
+    for helper in targets
+        .iter()
+        .flat_map(|target| &target.script.helpers)
+        .collect::<BTreeSet<_>>()
+    {
+        if !helpers.is_empty() {
+            helpers.push('\n');
+        }
+        helpers.push_str(helper.source());
+        helpers.push('\n');
+    }
+    let uses_context = targets.iter().any(|target| target.script.uses_context);
+
+    Ok(match tool {
+        Tool::Make => makefile(
+            path,
+            &variables,
+            &targets,
+            default_target,
+            &helpers,
+            uses_context,
+        ),
+        Tool::Just => justfile(
+            path,
+            &variables,
+            &targets,
+            default_target,
+            &helpers,
+            uses_context,
+        ),
+    })
+}
+
+/// Whether a base stage's instruction shapes the stages built on it
+fn sets_up(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.kind,
+        InstructionKind::Arg { .. } | InstructionKind::Env(_) | InstructionKind::Workdir(_)
+    )
+}
+
+fn makefile(
+    path: &Path,
+    variables: &[Variable],
+    targets: &[Target],
+    default_target: Option<String>,
+    helpers: &str,
+    uses_context: bool,
+) -> String {
+    let mut output = format!(
+        "# Generated by dfrun from {}. Each target runs a build stage after the\n\
+         # stages it depends on; ARGs are variables, e.g. `make NAME=value`.\n\
+         SHELL := bash\n\
+         .SHELLFLAGS := -euo pipefail -c\n\
+         .ONESHELL:\n",
+        path.display()
+    );
+    if let Some(target) = default_target {
+        output.push_str(&format!(".DEFAULT_GOAL := {}\n", target));
+    }
+    for variable in variables {
+        output.push_str(&format!("\n# {}\n", variable.declared_by));
+        let default = variable.default.as_deref().unwrap_or("");
+        let assignment = format!(
+            "{} ?= {}",
+            variable.name,
+            default.replace('$', "$$").replace('#', "\\#")
+        );
+        output.push_str(assignment.trim_end());
+        output.push_str(&format!("\nexport {}\n", variable.name));
+    }
+    if uses_context {
+        output.push_str(
+            "\n# COPY and ADD read local files from here\n\
+             DFRUN_CONTEXT ?= $(patsubst %/,%,$(dir $(abspath $(lastword $(MAKEFILE_LIST)))))\n\
+             export DFRUN_CONTEXT\n",
+        );
+    }
+    if !helpers.is_empty() {
+        output.push_str("\ndefine DFRUN_HELPERS\n");
+        output.push_str(&helpers.replace('$', "$$"));
+        output.push_str("endef\nexport DFRUN_HELPERS\n");
+    }
+    let names: Vec<&str> = targets.iter().map(|target| target.name.as_str()).collect();
+    output.push_str(&format!("\n.PHONY: {}\n", names.join(" ")));
+    for target in targets {
+        output.push('\n');
+        if let Some(from) = &target.from {
+            output.push_str(&format!("# {}\n", from));
+        }
+        output.push_str(&target.name);
+        output.push(':');
+        for dependency in &target.dependencies {
+            output.push(' ');
+            output.push_str(dependency);
+        }
+        output.push('\n');
+        // With .ONESHELL, a leading @ keeps make from echoing the whole recipe
+        let mut recipe = String::from("@");
+        if !helpers.is_empty() {
+            recipe.push_str("eval \"$DFRUN_HELPERS\"\n");
+        }
+        recipe.push_str(&target.script.body);
+        for line in recipe.replace('$', "$$").lines() {
+            output.push('\t');
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn justfile(
+    path: &Path,
+    variables: &[Variable],
+    targets: &[Target],
+    default_target: Option<String>,
+    helpers: &str,
+    uses_context: bool,
+) -> String {
+    let mut output = format!(
+        "# Generated by dfrun from {}. Each recipe runs a build stage after the\n\
+         # stages it depends on; ARGs are variables, e.g. `just NAME=value`.\n\
+         set export\n",
+        path.display()
+    );
+    for variable in variables {
+        output.push_str(&format!("\n# {}\n", variable.declared_by));
+        // As with make, the environment can set the variable too
+        output.push_str(&format!(
+            "{} := env_var_or_default({}, {})\n",
+            variable.name,
+            just_string(&variable.name),
+            just_string(variable.default.as_deref().unwrap_or(""))
+        ));
+    }
+    if uses_context {
+        output.push_str(
+            "\n# COPY and ADD read local files from here\n\
+             DFRUN_CONTEXT := justfile_directory()\n",
+        );
+    }
+    if !helpers.is_empty() {
+        output.push_str("\nDFRUN_HELPERS := '''\n");
+        output.push_str(helpers);
+        output.push_str("'''\n");
+    }
+    if let Some(target) = default_target {
+        output.push_str(&format!("\ndefault: {}\n", target));
+    }
+    for target in targets {
+        output.push('\n');
+        if let Some(from) = &target.from {
+            output.push_str(&format!("# {}\n", from));
+        }
+        output.push_str(&target.name);
+        output.push(':');
+        for dependency in &target.dependencies {
+            output.push(' ');
+            output.push_str(dependency);
+        }
+        output.push_str("\n    #!/usr/bin/env bash\n    set -euo pipefail\n");
+        if !helpers.is_empty() {
+            output.push_str("    eval \"$DFRUN_HELPERS\"\n");
+        }
+        for line in target.script.body.replace("{{", "{{{{").lines() {
+            if !line.is_empty() {
+                output.push_str("    ");
+                output.push_str(line);
+            }
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Quotes a value as a just string
+fn just_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,185 @@
+//! Git repositories as ADD sources, cloned with the `git` command.
+
+use crate::executor::Context;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A git repository named by ADD, e.g. `https://github.com/org/repo.git#v1.2:docs`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSource {
+    /// The repository, without the ref and subdirectory
+    pub url: String,
+    /// Branch, tag or commit to check out. Defaults to the remote's HEAD.
+    pub reference: Option<String>,
+    /// Directory within the repository to copy instead of the whole tree
+    pub subdir: Option<String>,
+}
+
+impl GitSource {
+    /// Recognizes the git source forms Docker accepts: `git@host:path`,
+    /// `git://` and `ssh://` URLs, and other URLs whose path ends in `.git`.
+    /// The ref and subdirectory come from a `#ref:subdir` fragment or from
+    /// `ref` and `subdir` query parameters.
+    pub fn parse(source: &str) -> Option<GitSource> {
+        let (base, fragment) = match source.split_once('#') {
+            Some((base, fragment)) => (base, Some(fragment)),
+            None => (source, None),
+        };
+        let (url, query) = match base.split_once('?') {
+            Some((url, query)) => (url, Some(query)),
+            None => (base, None),
+        };
+        let has_scheme = ["http://", "https://", "file://"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme));
+        let is_git = ["git@", "git://", "ssh://"]
+            .iter()
+            .any(|prefix| url.starts_with(prefix))
+            || (has_scheme && url.trim_end_matches('/').ends_with(".git"));
+        if !is_git {
+            return None;
+        }
+
+        let non_empty = |value: &str| (!value.is_empty()).then(|| value.to_string());
+        let mut reference = None;
+        let mut subdir = None;
+        if let Some(fragment) = fragment {
+            let (fragment_ref, fragment_subdir) = match fragment.split_once(':') {
+                Some((reference, subdir)) => (reference, Some(subdir)),
+                None => (fragment, None),
+            };
+            reference = non_empty(fragment_ref);
+            subdir = fragment_subdir.and_then(non_empty);
+        }
+        for param in query.unwrap_or("").split('&') {
+            match param.split_once('=') {
+                Some(("ref" | "tag" | "branch", value)) => reference = non_empty(value),
+                Some(("subdir", value)) => subdir = non_empty(value),
+                _ => {}
+            }
+        }
+        Some(GitSource {
+            url: url.to_string(),
+            reference,
+            subdir,
+        })
+    }
+}
+
+/// Full commit ids can't be cloned with `--branch`, so they are checked out
+/// after a full clone instead
+fn is_commit(reference: &str) -> bool {
+    matches!(reference.len(), 40 | 64) && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn git(args: &[&str], dir: Option<&Path>, context: &Context) -> io::Result<()> {
+    let mut command = Command::new("git");
+    command.args(args).envs(context.env);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let status = command.status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "git {} failed with {}",
+            args[0], status
+        )));
+    }
+    Ok(())
+}
+
+/// Clones `source` and copies its tree (or subdirectory) into `dest`. The
+/// `.git` directory is only kept with `keep_git_dir`.
+pub(crate) fn fetch(
+    source: &GitSource,
+    dest: &Path,
+    keep_git_dir: bool,
+    context: &Context,
+) -> io::Result<()> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let checkout = env::temp_dir().join(format!("dfrun-git-{}-{}", process::id(), nanos));
+    let result = clone(source, &checkout, context).and_then(|()| {
+        let tree = match &source.subdir {
+            Some(subdir) => checkout.join(subdir.trim_start_matches('/')),
+            None => checkout.clone(),
+        };
+        if !tree.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} not found in {}",
+                    source.subdir.as_deref().unwrap_or(""),
+                    source.url
+                ),
+            ));
+        }
+        let skip_git_dir = !keep_git_dir || source.subdir.is_some();
+        copy_tree(&tree, dest, skip_git_dir)
+    });
+    fs::remove_dir_all(&checkout).ok();
+    result
+}
+
+fn clone(source: &GitSource, checkout: &Path, context: &Context) -> io::Result<()> {
+    let checkout_arg = checkout.to_string_lossy();
+    let mut args = vec!["clone", "--quiet", "--recurse-submodules"];
+    match source.reference.as_deref() {
+        Some(reference) if is_commit(reference) => {}
+        Some(reference) => args.extend(["--depth", "1", "--branch", reference]),
+        None => args.extend(["--depth", "1"]),
+    }
+    args.extend([source.url.as_str(), &checkout_arg]);
+    git(&args, None, context)?;
+
+    if let Some(reference) = source.reference.as_deref().filter(|r| is_commit(r)) {
+        git(&["checkout", "--quiet", reference], Some(checkout), context)?;
+        git(
+            &["submodule", "update", "--quiet", "--init", "--recursive"],
+            Some(checkout),
+            context,
+        )?;
+    }
+    Ok(())
+}
+
+/// Copies the contents of `from` into `to`, keeping symlinks as links
+fn copy_tree(from: &Path, to: &Path, skip_git_dir: bool) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if skip_git_dir && entry.file_name() == ".git" {
+            continue;
+        }
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            // Submodules have their own .git, which is dropped along with the top level one
+            copy_tree(&entry.path(), &target, skip_git_dir)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        fs::remove_file(to)?;
+    }
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(from, to).map(|_| ())
+}
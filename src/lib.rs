@@ -0,0 +1,304 @@
+//! Runs Dockerfile instructions directly as shell commands.
+//!
+//! [`run`] parses a Dockerfile and executes it with the default
+//! [`ShellExecutor`]. Use [`run_with_executor`] to supply a different
+//! [`Executor`], or [`parser::parse`] to work with the parsed Dockerfile alone.
+//! [`export()`] compiles a Dockerfile into a script that runs without dfrun.
+
+mod archive;
+mod cache;
+mod checksum;
+mod context;
+mod download;
+mod error;
+pub mod executor;
+mod expand;
+mod export;
+mod git;
+pub mod parser;
+mod runner;
+#[cfg(test)]
+mod tests;
+
+pub use cache::{Cache, Pruned};
+pub use checksum::{Algorithm, Checksum};
+pub use error::{Error, FailedStep};
+pub use executor::{Context, CopyOptions, Destination, Executor, PlanExecutor, ShellExecutor};
+pub use expand::SubstitutionError;
+pub use export::ExportFormat;
+pub use git::GitSource;
+pub use parser::{Dockerfile, Instruction, InstructionKind, Stage};
+
+use colored::*;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Settings for a run
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Path to the Dockerfile
+    pub dockerfile: PathBuf,
+    /// Directory the run starts in. Defaults to the current directory.
+    pub workdir: Option<PathBuf>,
+    /// Build context COPY and ADD read local files from. Defaults to the
+    /// Dockerfile's directory.
+    pub context: Option<PathBuf>,
+    /// Print what each instruction does
+    pub debug: bool,
+    /// Keep running after a step fails instead of stopping
+    pub keep_going: bool,
+    /// Stage to build, along with the stages it depends on. Defaults to the last stage.
+    pub target: Option<String>,
+    /// Directory holding a root of its own for each stage built on an image,
+    /// so that `COPY --from` finds what that stage wrote. Without it, every
+    /// stage's root is `/`.
+    pub stage_dir: Option<PathBuf>,
+    /// Warn where the Dockerfile relies on dfrun behaving more loosely than
+    /// Docker, e.g. by using host environment variables it never declares
+    pub docker_compat: bool,
+    /// ARG values, which take precedence over the environment and defaults.
+    /// A name without a value, from a bare `--build-arg KEY` with `KEY` unset,
+    /// leaves the ARG to its other sources.
+    pub build_args: BTreeMap<String, Option<String>>,
+    /// File of `KEY=VALUE` lines with more ARG values. Those in `build_args` win.
+    pub build_arg_file: Option<PathBuf>,
+    /// Print what the run would do instead of doing it
+    pub dry_run: bool,
+    /// Fail when an instruction uses an undefined variable instead of
+    /// warning and substituting an empty string
+    pub strict: bool,
+    /// Prompt on stdin for ARG values
+    pub interactive: bool,
+    /// Fetch every ADD URL again instead of using the download cache
+    pub no_cache: bool,
+    /// Where downloads are cached. Defaults to [`Cache::default_dir`].
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            dockerfile: PathBuf::from("Dockerfile"),
+            workdir: None,
+            context: None,
+            debug: false,
+            keep_going: false,
+            target: None,
+            stage_dir: None,
+            docker_compat: false,
+            build_args: BTreeMap::new(),
+            build_arg_file: None,
+            strict: false,
+            dry_run: false,
+            interactive: false,
+            no_cache: false,
+            cache_dir: None,
+        }
+    }
+}
+
+/// Outcome of a run that was not stopped by an error
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// Number of instructions executed
+    pub instructions: usize,
+    /// Steps that failed while running with `keep_going`
+    pub failures: Vec<FailedStep>,
+}
+
+impl Report {
+    pub fn success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Reads and parses the Dockerfile named by `options`
+pub fn load(options: &Options) -> Result<Dockerfile, Error> {
+    let path = &options.dockerfile;
+    if options.debug {
+        println!(
+            "{} {}",
+            "DEBUG:".bright_blue().bold(),
+            format!("Reading Dockerfile from: {}", path.display()).bright_white()
+        );
+    }
+    if fs::metadata(path).is_err() {
+        return Err(Error::NotFound(path.clone()));
+    }
+    let source = fs::read_to_string(path).map_err(|e| Error::Read(path.clone(), e))?;
+    Ok(parser::parse(&source)?)
+}
+
+/// Runs a Dockerfile with bash, or with `dry_run` prints the plan of what
+/// running it would do
+pub fn run(options: &Options) -> Result<Report, Error> {
+    if options.dry_run {
+        return run_with_executor(options, &mut PlanExecutor::new(io::stdout()));
+    }
+    let cache = if options.no_cache {
+        None
+    } else {
+        options
+            .cache_dir
+            .clone()
+            .or_else(Cache::default_dir)
+            .map(Cache::new)
+    };
+    run_with_executor(options, &mut ShellExecutor { cache })
+}
+
+/// Runs a Dockerfile, carrying out each step with `executor`. Only the target
+/// stage and the stages it depends on are run.
+pub fn run_with_executor(options: &Options, executor: &mut dyn Executor) -> Result<Report, Error> {
+    let dockerfile = load(options)?;
+    let stages = dockerfile.stages();
+    let needed = needed_stages(&stages, options.target.as_deref())?;
+    let build_args = load_build_args(options)?;
+    let directives = &dockerfile.directives;
+    if let Some(warning) = directives.syntax.as_deref().and_then(syntax_warning) {
+        eprintln!("{} {}", "Warning:".yellow().bold(), warning.bright_white());
+    }
+    let mut runner = runner::Runner::new(options, build_args, directives.escape, executor)?;
+    if !directives.check.skip.is_empty() || directives.check.error {
+        runner.debug(
+            format!(
+                "Build checks: skip {:?}, error {} (dfrun does not run them)",
+                directives.check.skip, directives.check.error
+            )
+            .yellow(),
+        );
+    }
+    let preamble = dockerfile.preamble();
+    if let Some(instruction) = preamble
+        .iter()
+        .find(|instruction| !matches!(instruction.kind, InstructionKind::Arg { .. }))
+    {
+        runner.warn_compat(
+            instruction,
+            "Docker only allows ARG before the first FROM".to_string(),
+        );
+    }
+    for instruction in preamble {
+        runner.execute(instruction)?;
+    }
+    for stage in &stages {
+        if !needed[stage.index] {
+            runner.debug(
+                format!(
+                    "Skipping stage {} ({}): not needed for the target",
+                    stage.name.as_deref().unwrap_or(&stage.index.to_string()),
+                    stage.from.line_range()
+                )
+                .yellow(),
+            );
+            continue;
+        }
+        runner.start_stage(stage)?;
+        for instruction in stage.instructions {
+            runner.execute(instruction)?;
+        }
+    }
+    Ok(runner.finish())
+}
+
+/// Compiles a Dockerfile into `format`, covering the target stage and the
+/// stages it depends on. Variables are left for the result to substitute
+/// when it runs, so `build_args` and the environment play no part.
+pub fn export(options: &Options, format: ExportFormat) -> Result<String, Error> {
+    let dockerfile = load(options)?;
+    let stages = dockerfile.stages();
+    let needed = needed_stages(&stages, options.target.as_deref())?;
+    export::export(&dockerfile, &stages, &needed, format, &options.dockerfile)
+}
+
+/// Explains what dfrun lacks when `# syntax=` names a frontend other than
+/// the standard `docker/dockerfile` one
+fn syntax_warning(syntax: &str) -> Option<String> {
+    let image = syntax.strip_prefix("docker.io/").unwrap_or(syntax);
+    let (name, tag) = match image.split_once('@') {
+        Some((name, _digest)) => (name, None),
+        None => match image.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (image, None),
+        },
+    };
+    if name != "docker/dockerfile" {
+        Some(format!(
+            "# syntax={} names a custom frontend; dfrun only supports the standard Dockerfile syntax",
+            syntax
+        ))
+    } else if tag.is_some_and(|tag| tag.contains("labs")) {
+        Some(format!(
+            "# syntax={} enables experimental features, which dfrun does not support",
+            syntax
+        ))
+    } else {
+        None
+    }
+}
+
+/// Combines `build_arg_file` with `build_args`
+fn load_build_args(options: &Options) -> Result<BTreeMap<String, Option<String>>, Error> {
+    let mut build_args = match &options.build_arg_file {
+        Some(path) => fs::read_to_string(path)
+            .map(|contents| parse_build_arg_file(&contents))
+            .map_err(|e| Error::BuildArgFile(path.clone(), e))?,
+        None => BTreeMap::new(),
+    };
+    for (name, value) in &options.build_args {
+        // A name without a value does not hide a value from the file
+        if value.is_some() || !build_args.contains_key(name) {
+            build_args.insert(name.clone(), value.clone());
+        }
+    }
+    Ok(build_args)
+}
+
+/// Parses build args in the format of Docker's `--env-file`: one `KEY=VALUE`
+/// per line, with blank lines and `#` comments ignored. A line with only a
+/// key takes the value of that environment variable, if it is set.
+pub fn parse_build_arg_file(contents: &str) -> BTreeMap<String, Option<String>> {
+    contents
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_build_arg)
+        .collect()
+}
+
+/// Parses a `KEY=VALUE` build arg, or takes the value of the environment
+/// variable named by a bare `KEY`. The value is none when that variable is
+/// unset, as Docker then uses the ARG's default.
+pub fn parse_build_arg(arg: &str) -> (String, Option<String>) {
+    match arg.split_once('=') {
+        Some((key, value)) => (key.trim().to_string(), Some(value.to_string())),
+        None => {
+            let key = arg.trim();
+            (key.to_string(), env::var(key).ok())
+        }
+    }
+}
+
+/// Marks the stages that building `target` requires
+fn needed_stages(stages: &[Stage], target: Option<&str>) -> Result<Vec<bool>, Error> {
+    let mut needed = vec![false; stages.len()];
+    let target = match target {
+        Some(target) => parser::find_stage(stages, target)
+            .ok_or_else(|| Error::UnknownTarget(target.to_string()))?,
+        None => match stages.len().checked_sub(1) {
+            Some(last) => last,
+            None => return Ok(needed),
+        },
+    };
+    let mut pending = vec![target];
+    while let Some(index) = pending.pop() {
+        if !needed[index] {
+            needed[index] = true;
+            pending.extend(stages[index].dependencies(stages));
+        }
+    }
+    Ok(needed)
+}
@@ -1,23 +1,9 @@
 use clap::{Arg, Command};
 use colored::*;
-use regex::Regex;
-use std::env;
+use dfrun::{Cache, Error, ExportFormat, Options};
 use std::fs;
-use std::io::{self, BufRead, IsTerminal, Write};
-use std::process::Command as ProcessCommand;
-
-#[cfg(test)]
-mod tests;
-
-/// Expands environment variables in a string (supports $VAR and ${VAR} syntax)
-fn expand_env_vars(s: &str) -> String {
-    let re = Regex::new(r"\$\{?([A-Za-z_][A-Za-z0-9_]*)\}?").unwrap();
-    re.replace_all(s, |caps: &regex::Captures| {
-        let var_name = caps.get(1).unwrap().as_str();
-        env::var(var_name).unwrap_or_default()
-    })
-    .to_string()
-}
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
 
 fn main() {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
@@ -26,318 +12,278 @@ fn main() {
         .about("Runs a Dockerfile as a bash script")
         .arg(
             Arg::new("dockerfile")
+                .global(true)
                 .short('f')
                 .long("file")
                 .value_name("DOCKERFILE")
                 .help("Path to the Dockerfile. Default to Dockerfile in current directory.")
                 .default_value("Dockerfile"),
         )
+        .arg(
+            Arg::new("context")
+                .global(true)
+                .short('c')
+                .long("context")
+                .value_name("DIR")
+                .help("Build context for COPY and ADD. Default to the Dockerfile's directory."),
+        )
+        .arg(
+            Arg::new("target")
+                .global(true)
+                .short('t')
+                .long("target")
+                .value_name("STAGE")
+                .help("Build stage to run, along with the stages it depends on. Default to the last stage."),
+        )
+        .arg(
+            Arg::new("stage-dir")
+                .global(true)
+                .long("stage-dir")
+                .value_name("DIR")
+                .help("Give each stage its own root under DIR, so COPY --from can copy between stages"),
+        )
+        .arg(
+            Arg::new("build-arg")
+                .global(true)
+                .long("build-arg")
+                .value_name("KEY=VALUE")
+                .help("Set an ARG value, or take it from the environment when given only KEY. Can be repeated.")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("build-arg-file")
+                .global(true)
+                .long("build-arg-file")
+                .value_name("FILE")
+                .help("Read ARG values from a file of KEY=VALUE lines"),
+        )
         .arg(
             Arg::new("debug")
+                .global(true)
                 .short('d')
                 .long("debug")
                 .help("Enable debug logging")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("keep-going")
+                .global(true)
+                .short('k')
+                .long("keep-going")
+                .help("Keep running after a step fails and exit non-zero at the end")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .global(true)
+                .long("dry-run")
+                .help("Print what running the Dockerfile would do, without doing it")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("docker-compat")
+                .global(true)
+                .long("docker-compat")
+                .help("Warn where the Dockerfile relies on dfrun being looser than Docker")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict")
+                .global(true)
+                .long("strict")
+                .help("Fail when an instruction uses an undefined variable instead of warning")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no-cache")
+                .global(true)
+                .long("no-cache")
+                .help("Download every ADD URL again instead of using the download cache")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("plan").about("Print what running the Dockerfile would do, the same as --dry-run"),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Compile the Dockerfile into a script, Makefile, justfile or GitHub Actions workflow that runs without dfrun")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("What to compile the Dockerfile into")
+                        .value_parser(ExportFormat::NAMES.to_vec())
+                        .default_value("bash"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Write to FILE instead of standard output"),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Manage the download cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("prune")
+                        .about("Remove cached downloads, least recently used first")
+                        .arg(
+                            Arg::new("max-size")
+                                .long("max-size")
+                                .value_name("SIZE")
+                                .help("Keep up to SIZE of downloads, e.g. 500M or 10G. Default to removing everything.")
+                                .value_parser(parse_size),
+                        ),
+                ),
+        )
         .get_matches();
 
-    let debug_enabled = matches.get_flag("debug");
-    let dockerfile_path = matches.get_one::<String>("dockerfile").unwrap();
+    if let Some(("cache", cache_matches)) = matches.subcommand() {
+        if let Some(("prune", prune_matches)) = cache_matches.subcommand() {
+            let max_size = prune_matches.get_one::<u64>("max-size").copied();
+            prune_cache(max_size.unwrap_or(0));
+        }
+        return;
+    }
 
-    if debug_enabled {
-        println!(
-            "{} {}",
-            "DEBUG:".bright_blue().bold(),
-            format!("Reading Dockerfile from: {}", dockerfile_path).bright_white()
-        );
+    let options = Options {
+        dockerfile: PathBuf::from(matches.get_one::<String>("dockerfile").unwrap()),
+        workdir: None,
+        context: matches.get_one::<String>("context").map(PathBuf::from),
+        debug: matches.get_flag("debug"),
+        keep_going: matches.get_flag("keep-going"),
+        target: matches.get_one::<String>("target").cloned(),
+        stage_dir: matches.get_one::<String>("stage-dir").map(PathBuf::from),
+        docker_compat: matches.get_flag("docker-compat"),
+        strict: matches.get_flag("strict"),
+        dry_run: matches.get_flag("dry-run") || matches.subcommand_name() == Some("plan"),
+        build_args: matches
+            .get_many::<String>("build-arg")
+            .unwrap_or_default()
+            .map(|arg| dfrun::parse_build_arg(arg))
+            .collect(),
+        build_arg_file: matches
+            .get_one::<String>("build-arg-file")
+            .map(PathBuf::from),
+        interactive: io::stdin().is_terminal(),
+        no_cache: matches.get_flag("no-cache"),
+        cache_dir: None,
+    };
+
+    if let Some(("export", export_matches)) = matches.subcommand() {
+        let format = export_matches.get_one::<String>("format").unwrap();
+        let output = export_matches
+            .get_one::<String>("output")
+            .map(PathBuf::from);
+        export(&options, format.parse().unwrap(), output);
+        return;
     }
 
-    // Check if file exists first
-    if fs::metadata(dockerfile_path).is_err() {
+    let report = match dfrun::run(&options) {
+        Ok(report) => report,
+        Err(e) => exit_with_error(&e),
+    };
+
+    if !report.success() {
         eprintln!(
             "{} {}",
             "Error:".red().bold(),
-            format!("Dockerfile not found at: {}", dockerfile_path).bright_white()
+            format!("{} step(s) failed:", report.failures.len()).bright_white()
         );
+        for failure in &report.failures {
+            eprintln!(
+                "  line {}: {} (exit code {})",
+                failure.start_line, failure.instruction, failure.code
+            );
+        }
+        std::process::exit(1);
+    }
+}
+
+fn exit_with_error(e: &Error) -> ! {
+    eprintln!("{} {}", "Error:".red().bold(), e.to_string().bright_white());
+    if let Error::NotFound(_) = e {
         eprintln!(
             "{} {}",
             "Hint:".yellow().bold(),
             "Make sure the Dockerfile exists in the specified path or use -f/--file to specify a different path.".bright_white()
         );
-        std::process::exit(1);
     }
+    std::process::exit(e.exit_code());
+}
+
+/// Prints the exported Dockerfile, or writes it to `output` as an executable file
+fn export(options: &Options, format: ExportFormat, output: Option<PathBuf>) {
+    let exported = match dfrun::export(options, format) {
+        Ok(exported) => exported,
+        Err(e) => exit_with_error(&e),
+    };
+    let Some(path) = output else {
+        print!("{}", exported);
+        return;
+    };
+    if let Err(e) = fs::write(&path, exported) {
+        exit_with_error(&Error::Io(io::Error::new(
+            e.kind(),
+            format!("Failed to write {}: {}", path.display(), e),
+        )));
+    }
+    #[cfg(unix)]
+    if format == ExportFormat::Bash {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).ok();
+    }
+}
+
+/// Parses a size such as `1024`, `500M` or `10G` (powers of 1024) into bytes
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {}", value))?;
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit: {}", unit)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size too large: {}", value))
+}
 
-    let file = match fs::File::open(dockerfile_path) {
-        Ok(file) => file,
+fn prune_cache(max_size: u64) {
+    let Some(dir) = Cache::default_dir() else {
+        eprintln!(
+            "{} {}",
+            "Error:".red().bold(),
+            "Could not determine the cache directory".bright_white()
+        );
+        std::process::exit(1);
+    };
+    match Cache::new(&dir).prune(max_size) {
+        Ok(pruned) => println!(
+            "Removed {} file(s) from {}, freeing {:.1} MiB",
+            pruned.files,
+            dir.display(),
+            pruned.bytes as f64 / (1024.0 * 1024.0)
+        ),
         Err(e) => {
             eprintln!(
                 "{} {}",
                 "Error:".red().bold(),
-                format!("Failed to open Dockerfile: {}", e).bright_white()
+                format!("Failed to prune {}: {}", dir.display(), e).bright_white()
             );
             std::process::exit(1);
         }
-    };
-    let reader = io::BufReader::new(file);
-
-    let run_re = Regex::new(r"^RUN\s+(.*)").unwrap();
-    let add_re = Regex::new(r"^ADD\s+(https?://\S+)\s+.*").unwrap();
-    let env_re = Regex::new(r"^ENV\s+(\S+?)(?:=|\s+)(.+)").unwrap();
-    let arg_re = Regex::new(r"^ARG\s+([^=\s]+)(?:\s*=\s*(.+))?").unwrap();
-    let workdir_re = Regex::new(r"^WORKDIR\s+(.+)").unwrap();
-
-    let mut run_command = String::new();
-    let mut in_run_block = false;
-    let workdir = env::current_dir().unwrap();
-
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line").trim().to_string();
-        if debug_enabled {
-            println!(
-                "{} {}",
-                "DEBUG:".bright_blue().bold(),
-                format!("Processing line: {}", line).bright_white()
-            );
-        }
-
-        if in_run_block {
-            if line.ends_with("\\") {
-                if debug_enabled {
-                    println!(
-                        "{} {}",
-                        "DEBUG:".bright_blue().bold(),
-                        "Action: Continuing multi-line RUN command".yellow()
-                    );
-                }
-                if let Some(stripped) = line.strip_suffix("\\") {
-                    run_command.push_str(stripped);
-                    run_command.push(' ');
-                }
-            } else {
-                run_command.push_str(&line);
-                if debug_enabled {
-                    println!(
-                        "{} {}",
-                        "DEBUG:".bright_blue().bold(),
-                        format!(
-                            "Action: Executing multi-line command in {}: {}",
-                            workdir.display(),
-                            run_command
-                        )
-                        .green()
-                    );
-                }
-                let current_dir = env::current_dir().unwrap();
-                env::set_current_dir(&workdir).expect("Failed to change directory");
-                let status = ProcessCommand::new("bash")
-                    .arg("-c")
-                    .arg(&run_command)
-                    .status()
-                    .expect("Failed to execute command");
-                env::set_current_dir(current_dir).expect("Failed to restore directory");
-                if !status.success() {
-                    eprintln!("Command failed with status: {}", status);
-                }
-                run_command.clear();
-                in_run_block = false;
-            }
-        } else if let Some(caps) = workdir_re.captures(&line) {
-            let dir = caps.get(1).unwrap().as_str();
-            if debug_enabled {
-                println!(
-                    "{} {}",
-                    "DEBUG:".bright_blue().bold(),
-                    format!("Action: Ignoring WORKDIR instruction: {}", dir).cyan()
-                );
-            }
-        } else if let Some(caps) = run_re.captures(&line) {
-            let command = caps.get(1).unwrap().as_str();
-            if command.ends_with("\\") {
-                if debug_enabled {
-                    println!(
-                        "{} {}",
-                        "DEBUG:".bright_blue().bold(),
-                        "Action: Starting multi-line RUN command".yellow()
-                    );
-                }
-                if let Some(stripped) = command.strip_suffix("\\") {
-                    run_command.push_str(stripped);
-                    run_command.push(' ');
-                }
-                in_run_block = true;
-            } else {
-                if debug_enabled {
-                    println!(
-                        "{} {}",
-                        "DEBUG:".bright_blue().bold(),
-                        format!(
-                            "Action: Executing command in {}: {}",
-                            workdir.display(),
-                            command
-                        )
-                        .green()
-                    );
-                }
-                let current_dir = env::current_dir().unwrap();
-                env::set_current_dir(&workdir).expect("Failed to change directory");
-                let status = ProcessCommand::new("bash")
-                    .arg("-c")
-                    .arg(command)
-                    .status()
-                    .expect("Failed to execute command");
-                env::set_current_dir(current_dir).expect("Failed to restore directory");
-                if !status.success() {
-                    eprintln!("Command failed with status: {}", status);
-                }
-            }
-        } else if let Some(caps) = add_re.captures(&line) {
-            let url = caps.get(1).unwrap().as_str();
-            if debug_enabled {
-                println!(
-                    "{} {}",
-                    "DEBUG:".bright_blue().bold(),
-                    format!("Action: Downloading from URL: {}", url).cyan()
-                );
-            }
-            let current_dir = env::current_dir().unwrap();
-            env::set_current_dir(&workdir).expect("Failed to change directory");
-            let status = ProcessCommand::new("curl")
-                .args(["-O", url])
-                .status()
-                .expect("Failed to execute curl");
-            env::set_current_dir(current_dir).expect("Failed to restore directory");
-            if !status.success() {
-                eprintln!("Download failed with status: {}", status);
-            }
-        } else if let Some(caps) = env_re.captures(&line) {
-            let key = caps.get(1).unwrap().as_str();
-            let raw_value = caps.get(2).unwrap().as_str();
-            let value = expand_env_vars(raw_value);
-            if debug_enabled {
-                println!(
-                    "{} {}",
-                    "DEBUG:".bright_blue().bold(),
-                    format!(
-                        "Action: Setting environment variable: {}={} (expanded from {})",
-                        key, value, raw_value
-                    )
-                    .magenta()
-                );
-            }
-            env::set_var(key, value);
-        } else if let Some(caps) = arg_re.captures(&line) {
-            let key = caps.get(1).unwrap().as_str().to_string();
-            let default_value = caps.get(2).map(|v| v.as_str().to_string());
-            let env_value = env::var(&key).ok();
-            let is_interactive = io::stdin().is_terminal();
-
-            if debug_enabled {
-                println!(
-                    "{} {}",
-                    "DEBUG:".bright_blue().bold(),
-                    format!(
-                        "Action: Found ARG: {} (default: {:?}, env: {:?}, interactive: {})",
-                        key, default_value, env_value, is_interactive
-                    )
-                    .yellow()
-                );
-            }
-
-            let value = if is_interactive {
-                // Interactive mode: prompt for input
-                let prompt_default = default_value.as_ref().or(env_value.as_ref());
-                print!(
-                    "Enter value for ARG {}{}: ",
-                    key,
-                    prompt_default.map_or("".to_string(), |v| format!(" (default: {})", v))
-                );
-                io::stdout().flush().expect("Failed to flush stdout");
-                let mut input = String::new();
-                io::stdin()
-                    .read_line(&mut input)
-                    .expect("Failed to read input");
-                let input = input.trim();
-                if input.is_empty() {
-                    if let Some(val) = default_value.or(env_value) {
-                        if debug_enabled {
-                            println!(
-                                "{} {}",
-                                "DEBUG:".bright_blue().bold(),
-                                format!("Action: Using default/env value: {}", val).green()
-                            );
-                        }
-                        val
-                    } else {
-                        eprintln!(
-                            "{} {}",
-                            "Error:".red().bold(),
-                            format!("No value provided for ARG {}", key).bright_white()
-                        );
-                        std::process::exit(1);
-                    }
-                } else {
-                    if debug_enabled {
-                        println!(
-                            "{} {}",
-                            "DEBUG:".bright_blue().bold(),
-                            format!("Action: Using provided value: {}", input).green()
-                        );
-                    }
-                    input.to_string()
-                }
-            } else {
-                // Non-interactive mode: use env value, then default, or error
-                if let Some(val) = env_value {
-                    if debug_enabled {
-                        println!(
-                            "{} {}",
-                            "DEBUG:".bright_blue().bold(),
-                            format!("Action: Using environment value: {}", val).green()
-                        );
-                    }
-                    val
-                } else if let Some(val) = default_value {
-                    if debug_enabled {
-                        println!(
-                            "{} {}",
-                            "DEBUG:".bright_blue().bold(),
-                            format!("Action: Using default value: {}", val).green()
-                        );
-                    }
-                    val
-                } else {
-                    eprintln!(
-                        "{} {}",
-                        "Error:".red().bold(),
-                        format!(
-                            "No value provided for ARG {} (non-interactive mode requires default or environment variable)",
-                            key
-                        )
-                        .bright_white()
-                    );
-                    std::process::exit(1);
-                }
-            };
-
-            if debug_enabled {
-                println!(
-                    "{} {}",
-                    "DEBUG:".bright_blue().bold(),
-                    format!("Action: Setting ARG variable: {}={}", key, value).magenta()
-                );
-            }
-            env::set_var(&key, &value);
-        } else if !line.is_empty() && !line.starts_with('#') && debug_enabled {
-            println!(
-                "{} {}",
-                "DEBUG:".bright_blue().bold(),
-                format!("Original command: {}", line).bright_white()
-            );
-            println!(
-                "{} {}",
-                "DEBUG:".bright_blue().bold(),
-                "Action: Ignoring unsupported instruction".red()
-            );
-        }
     }
 }
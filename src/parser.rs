@@ -0,0 +1,795 @@
+//! Parses a Dockerfile into a typed list of instructions.
+//!
+//! The grammar follows BuildKit's: an instruction is a case-insensitive
+//! keyword followed by arguments, a trailing `\` continues the instruction on
+//! the next line (skipping comment and blank lines in between), and
+//! leading `--name=value` words are flags. RUN, COPY and ADD may reference
+//! heredocs (`<<EOF`) whose bodies follow the instruction. Parser directives
+//! such as `# escape=` may precede everything else.
+
+use std::fmt;
+use std::iter::Peekable;
+
+/// A parsed Dockerfile
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dockerfile {
+    pub directives: Directives,
+    pub instructions: Vec<Instruction>,
+}
+
+/// Parser directives: `# name=value` comments at the very top of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directives {
+    /// Character that escapes the next one and continues lines: `\` unless
+    /// `# escape=` sets it to `` ` ``
+    pub escape: char,
+    /// The frontend image named by `# syntax=`, e.g. `docker/dockerfile:1`
+    pub syntax: Option<String>,
+    /// Lint settings from `# check=`
+    pub check: Check,
+}
+
+impl Default for Directives {
+    fn default() -> Self {
+        Directives {
+            escape: '\\',
+            syntax: None,
+            check: Check::default(),
+        }
+    }
+}
+
+/// Build check settings, e.g. `# check=skip=JSONArgsRecommended;error=true`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Check {
+    /// Names of checks to skip, or `all`
+    pub skip: Vec<String>,
+    /// Whether check warnings fail the build
+    pub error: bool,
+}
+
+/// A build stage: a FROM instruction and the instructions up to the next one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage<'a> {
+    /// Position among the Dockerfile's stages, starting at 0
+    pub index: usize,
+    /// Name given with `FROM ... AS name`, lowercased as Docker does
+    pub name: Option<String>,
+    pub from: &'a Instruction,
+    /// The earlier stage this one builds on, when FROM names one
+    pub base: Option<usize>,
+    /// Instructions after the FROM
+    pub instructions: &'a [Instruction],
+}
+
+/// A single instruction along with where it came from in the Dockerfile
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub kind: InstructionKind,
+    pub flags: Vec<Flag>,
+    /// First line of the instruction (1-based)
+    pub start_line: usize,
+    /// Last line of the instruction, after following line continuations
+    pub end_line: usize,
+    /// The instruction as written, with line continuations joined
+    pub original: String,
+    /// Where each argument starts in `original`, in characters: the image for
+    /// FROM, each value for ENV and LABEL, the default for ARG, the directory
+    /// for WORKDIR, and the sources followed by the destination for ADD and
+    /// COPY. The elements of a JSON array all point at its `[`.
+    pub offsets: Vec<usize>,
+    /// Bodies of the heredocs the instruction references, in order
+    pub heredocs: Vec<Heredoc>,
+}
+
+/// A heredoc body, e.g. the lines between `RUN <<EOF` and `EOF`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heredoc {
+    /// The delimiter, without quotes
+    pub name: String,
+    /// The body, including its trailing newline. Leading tabs are already
+    /// stripped for `<<-` heredocs.
+    pub content: String,
+    /// False when the delimiter was quoted (`<<"EOF"`), which disables
+    /// variable expansion in the body
+    pub expand: bool,
+}
+
+/// A `--name` or `--name=value` flag preceding an instruction's arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Flag {
+    pub name: String,
+    pub value: Option<String>,
+    /// Where the value, or else the flag, starts in the instruction's
+    /// `original`, in characters
+    pub offset: usize,
+}
+
+/// Shell form (`RUN echo hi`) or exec form (`RUN ["echo", "hi"]`) of a command
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandForm {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstructionKind {
+    From {
+        image: String,
+        name: Option<String>,
+    },
+    Run(CommandForm),
+    Cmd(CommandForm),
+    Entrypoint(CommandForm),
+    /// Names and values as written, with quotes; they are removed when the
+    /// values are expanded
+    Env(Vec<(String, String)>),
+    /// Same format as ENV
+    Label(Vec<(String, String)>),
+    Arg {
+        name: String,
+        default: Option<String>,
+    },
+    Workdir(String),
+    Add {
+        sources: Vec<String>,
+        dest: String,
+    },
+    Copy {
+        sources: Vec<String>,
+        dest: String,
+    },
+    /// Any instruction dfrun has no model for (EXPOSE, USER, ...)
+    Other {
+        keyword: String,
+        args: String,
+    },
+}
+
+/// An error in the Dockerfile, pointing at the line it was found on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "--{}={}", self.name, value),
+            None => write!(f, "--{}", self.name),
+        }
+    }
+}
+
+impl Instruction {
+    /// Returns the value of a flag, or an empty string for a flag given without one
+    pub fn flag(&self, name: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .find(|flag| flag.name == name)
+            .map(|flag| flag.value.as_deref().unwrap_or(""))
+    }
+
+    /// Human readable line reference, e.g. `line 3` or `lines 3-5`
+    pub fn line_range(&self) -> String {
+        line_range(self.start_line, self.end_line)
+    }
+
+    /// Returns the heredoc a COPY or ADD source such as `<<EOF` refers to
+    pub fn heredoc_source(&self, source: &str) -> Option<&Heredoc> {
+        let marker = parse_heredoc_marker(source)?;
+        self.heredocs
+            .iter()
+            .find(|heredoc| heredoc.name == marker.name)
+    }
+}
+
+impl Dockerfile {
+    /// Instructions before the first FROM. A Dockerfile without FROM is all preamble.
+    pub fn preamble(&self) -> &[Instruction] {
+        let first_from = self
+            .instructions
+            .iter()
+            .position(|instruction| matches!(instruction.kind, InstructionKind::From { .. }))
+            .unwrap_or(self.instructions.len());
+        &self.instructions[..first_from]
+    }
+
+    /// Splits the Dockerfile into its build stages
+    pub fn stages(&self) -> Vec<Stage<'_>> {
+        let mut stages: Vec<Stage> = Vec::new();
+        let mut start = self.preamble().len();
+        while start < self.instructions.len() {
+            let from = &self.instructions[start];
+            let end = self.instructions[start + 1..]
+                .iter()
+                .position(|instruction| matches!(instruction.kind, InstructionKind::From { .. }))
+                .map_or(self.instructions.len(), |offset| start + 1 + offset);
+            let InstructionKind::From { image, name } = &from.kind else {
+                unreachable!("stages start at FROM instructions");
+            };
+            stages.push(Stage {
+                index: stages.len(),
+                name: name.as_ref().map(|name| name.to_ascii_lowercase()),
+                from,
+                base: find_stage_by_name(&stages, image),
+                instructions: &self.instructions[start + 1..end],
+            });
+            start = end;
+        }
+        stages
+    }
+}
+
+impl Stage<'_> {
+    /// Earlier stages this one needs: its base and those named by `COPY --from`
+    pub fn dependencies(&self, stages: &[Stage]) -> Vec<usize> {
+        let earlier = &stages[..self.index.min(stages.len())];
+        let copy_sources =
+            self.instructions
+                .iter()
+                .filter_map(|instruction| match instruction.kind {
+                    InstructionKind::Copy { .. } => find_stage(earlier, instruction.flag("from")?),
+                    _ => None,
+                });
+        self.base.into_iter().chain(copy_sources).collect()
+    }
+}
+
+fn find_stage_by_name(stages: &[Stage], name: &str) -> Option<usize> {
+    stages
+        .iter()
+        .find(|stage| stage.name.as_deref() == Some(name.to_ascii_lowercase().as_str()))
+        .map(|stage| stage.index)
+}
+
+/// Finds a stage by name or by index, as `COPY --from` and `--target` refer to them
+pub fn find_stage(stages: &[Stage], reference: &str) -> Option<usize> {
+    find_stage_by_name(stages, reference).or_else(|| {
+        reference
+            .parse::<usize>()
+            .ok()
+            .filter(|&index| index < stages.len())
+    })
+}
+
+pub(crate) fn line_range(start_line: usize, end_line: usize) -> String {
+    if start_line == end_line {
+        format!("line {}", start_line)
+    } else {
+        format!("lines {}-{}", start_line, end_line)
+    }
+}
+
+/// Parses the contents of a Dockerfile
+pub fn parse(source: &str) -> Result<Dockerfile, ParseError> {
+    let mut instructions = Vec::new();
+    let mut lines = source.lines().enumerate().peekable();
+    let directives = parse_directives(&mut lines)?;
+    let escape = directives.escape;
+
+    while let Some((index, raw_line)) = lines.next() {
+        let line = raw_line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let start_line = index + 1;
+        let mut end_line = start_line;
+        let (text, mut continues) = trim_continuation(line, escape);
+        let mut logical = text.to_string();
+        while continues {
+            let Some((index, raw_line)) = lines.next() else {
+                break;
+            };
+            end_line = index + 1;
+            // Comments and blank lines inside a continuation are skipped
+            let trimmed = raw_line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let (text, more) = trim_continuation(raw_line, escape);
+            logical.push_str(text);
+            continues = more;
+        }
+
+        let mut instruction = parse_instruction(logical.trim(), start_line, end_line, escape)?;
+        for marker in heredoc_markers(&instruction.kind) {
+            let mut content = String::new();
+            loop {
+                let Some((index, raw_line)) = lines.next() else {
+                    return Err(ParseError {
+                        line: start_line,
+                        message: format!("unterminated heredoc {}", marker.name),
+                    });
+                };
+                instruction.end_line = index + 1;
+                let body_line = if marker.strip_tabs {
+                    raw_line.trim_start_matches('\t')
+                } else {
+                    raw_line
+                };
+                if body_line == marker.name {
+                    break;
+                }
+                content.push_str(body_line);
+                content.push('\n');
+            }
+            instruction.heredocs.push(Heredoc {
+                name: marker.name,
+                content,
+                expand: marker.expand,
+            });
+        }
+        instructions.push(instruction);
+    }
+
+    Ok(Dockerfile {
+        directives,
+        instructions,
+    })
+}
+
+/// Reads the parser directives at the top of the file. As in BuildKit, they
+/// end at the first line that is not a known directive; unknown directives
+/// are left to be skipped as comments.
+fn parse_directives<'a>(
+    lines: &mut Peekable<impl Iterator<Item = (usize, &'a str)>>,
+) -> Result<Directives, ParseError> {
+    let mut directives = Directives::default();
+    let mut seen = Vec::new();
+    while let Some(&(index, line)) = lines.peek() {
+        let Some((name, value)) = line
+            .trim()
+            .strip_prefix('#')
+            .and_then(|directive| directive.split_once('='))
+        else {
+            break;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if !["escape", "syntax", "check"].contains(&name.as_str()) {
+            break;
+        }
+        let error = |message: String| ParseError {
+            line: index + 1,
+            message,
+        };
+        if seen.contains(&name) {
+            return Err(error(format!(
+                "only one {} parser directive can be used",
+                name
+            )));
+        }
+        match name.as_str() {
+            "escape" => {
+                directives.escape = match value {
+                    "\\" => '\\',
+                    "`" => '`',
+                    _ => return Err(error(format!("invalid escape token '{}'", value))),
+                }
+            }
+            "syntax" => directives.syntax = Some(value.to_string()),
+            _ => directives.check = parse_check(value).map_err(error)?,
+        }
+        seen.push(name);
+        lines.next();
+    }
+    Ok(directives)
+}
+
+/// Parses a `# check=` value: `;`-separated `skip=<checks>` and `error=<bool>`
+fn parse_check(value: &str) -> Result<Check, String> {
+    let mut check = Check::default();
+    for setting in value.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+        match setting.split_once('=') {
+            Some(("skip", checks)) => {
+                check.skip = checks
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|check| !check.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            Some(("error", error)) => {
+                check.error = error
+                    .parse()
+                    .map_err(|_| format!("invalid check error value '{}'", error))?;
+            }
+            _ => return Err(format!("invalid check setting '{}'", setting)),
+        }
+    }
+    Ok(check)
+}
+
+/// Strips a trailing line continuation, reporting whether there was one
+fn trim_continuation(line: &str, escape: char) -> (&str, bool) {
+    match line.trim_end_matches([' ', '\t']).strip_suffix(escape) {
+        Some(stripped) => (stripped, true),
+        None => (line, false),
+    }
+}
+
+fn parse_instruction(
+    logical: &str,
+    start_line: usize,
+    end_line: usize,
+    escape: char,
+) -> Result<Instruction, ParseError> {
+    let error = |message: String| ParseError {
+        line: start_line,
+        message,
+    };
+
+    let (keyword, rest) = match logical.split_once(char::is_whitespace) {
+        Some((keyword, rest)) => (keyword, rest.trim()),
+        // An empty slice at the end, so that offsets can be taken from it
+        None => (logical, &logical[logical.len()..]),
+    };
+    // Keywords are case-insensitive, as in Docker
+    let keyword = keyword.to_ascii_uppercase();
+    let keyword = keyword.as_str();
+    let (mut flags, args) = split_flags(rest);
+    let flags_start = column(logical, rest);
+    for flag in &mut flags {
+        flag.offset += flags_start;
+    }
+    let mut offsets = Vec::new();
+
+    let kind = match keyword {
+        "FROM" => {
+            let words: Vec<&str> = args.split_whitespace().collect();
+            if let Some(image) = words.first() {
+                offsets.push(column(logical, image));
+            }
+            match words.as_slice() {
+                [image] => InstructionKind::From {
+                    image: image.to_string(),
+                    name: None,
+                },
+                [image, as_keyword, name] if as_keyword.eq_ignore_ascii_case("as") => {
+                    InstructionKind::From {
+                        image: image.to_string(),
+                        name: Some(name.to_string()),
+                    }
+                }
+                _ => {
+                    return Err(error(
+                        "FROM requires either one or three arguments".to_string(),
+                    ))
+                }
+            }
+        }
+        "RUN" | "CMD" | "ENTRYPOINT" => {
+            if args.is_empty() {
+                return Err(error(format!("{} requires at least one argument", keyword)));
+            }
+            let form = parse_command_form(args);
+            if form == CommandForm::Exec(Vec::new()) {
+                return Err(error(format!("{} requires at least one argument", keyword)));
+            }
+            match keyword {
+                "RUN" => InstructionKind::Run(form),
+                "CMD" => InstructionKind::Cmd(form),
+                _ => InstructionKind::Entrypoint(form),
+            }
+        }
+        "ENV" | "LABEL" => {
+            let mut pairs = Vec::new();
+            for (name, value) in parse_name_values(keyword, args, escape).map_err(error)? {
+                offsets.push(column(logical, value));
+                pairs.push((name, value.to_string()));
+            }
+            if keyword == "ENV" {
+                InstructionKind::Env(pairs)
+            } else {
+                InstructionKind::Label(pairs)
+            }
+        }
+        "ARG" => {
+            let (name, default) = match args.split_once('=') {
+                Some((name, default)) => {
+                    let default = default.trim();
+                    offsets.push(column(logical, default));
+                    (name.trim(), Some(default.to_string()))
+                }
+                None => (args.split_whitespace().next().unwrap_or(""), None),
+            };
+            if name.is_empty() {
+                return Err(error("ARG requires a name".to_string()));
+            }
+            InstructionKind::Arg {
+                name: name.to_string(),
+                default,
+            }
+        }
+        "WORKDIR" => {
+            if args.is_empty() {
+                return Err(error("WORKDIR requires exactly one argument".to_string()));
+            }
+            offsets.push(column(logical, args));
+            InstructionKind::Workdir(args.to_string())
+        }
+        "ADD" | "COPY" => {
+            let mut sources = match parse_json_array(args) {
+                Some(sources) => {
+                    offsets = vec![column(logical, args); sources.len()];
+                    sources
+                }
+                None => args
+                    .split_whitespace()
+                    .map(|word| {
+                        offsets.push(column(logical, word));
+                        word.to_string()
+                    })
+                    .collect(),
+            };
+            if sources.len() < 2 {
+                return Err(error(format!(
+                    "{} requires at least two arguments",
+                    keyword
+                )));
+            }
+            let dest = sources.pop().unwrap();
+            if keyword == "ADD" {
+                InstructionKind::Add { sources, dest }
+            } else {
+                InstructionKind::Copy { sources, dest }
+            }
+        }
+        _ => InstructionKind::Other {
+            keyword: keyword.to_string(),
+            args: args.to_string(),
+        },
+    };
+
+    Ok(Instruction {
+        kind,
+        flags,
+        start_line,
+        end_line,
+        original: logical.to_string(),
+        offsets,
+        heredocs: Vec::new(),
+    })
+}
+
+/// The offset in characters of `part`, a slice of `text`, from the start of `text`
+fn column(text: &str, part: &str) -> usize {
+    let start = part.as_ptr() as usize - text.as_ptr() as usize;
+    text[..start].chars().count()
+}
+
+/// A `<<EOF`, `<<-EOF` or `<<"EOF"` heredoc reference
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HeredocMarker {
+    pub(crate) name: String,
+    pub(crate) expand: bool,
+    pub(crate) strip_tabs: bool,
+}
+
+/// Parses a heredoc reference at the start of `text`, returning it along with
+/// the number of bytes it spans
+fn parse_heredoc_prefix(text: &str) -> Option<(HeredocMarker, usize)> {
+    let rest = text.strip_prefix("<<")?;
+    if rest.starts_with('<') {
+        // `<<<` is a here-string, not a heredoc
+        return None;
+    }
+    let (strip_tabs, rest) = match rest.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let unquoted = rest.trim_start();
+    let (name, expand, rest) = match unquoted.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            let (name, rest) = unquoted[1..].split_once(quote)?;
+            (name, false, rest)
+        }
+        _ => {
+            let end = unquoted
+                .find(|c: char| c.is_whitespace() || ";&|<>()".contains(c))
+                .unwrap_or(unquoted.len());
+            (&unquoted[..end], true, &unquoted[end..])
+        }
+    };
+    if name.is_empty() {
+        return None;
+    }
+    let marker = HeredocMarker {
+        name: name.to_string(),
+        expand,
+        strip_tabs,
+    };
+    Some((marker, text.len() - rest.len()))
+}
+
+/// Parses a word that consists of exactly one heredoc reference
+pub(crate) fn parse_heredoc_marker(word: &str) -> Option<HeredocMarker> {
+    match parse_heredoc_prefix(word) {
+        Some((marker, length)) if length == word.len() => Some(marker),
+        _ => None,
+    }
+}
+
+/// Finds heredoc references in a shell command, skipping quoted text. As in
+/// BuildKit, only a word starting with `<<` opens a heredoc, so `a<<b` and
+/// `$((1<<4))` do not.
+fn find_heredoc_markers(command: &str) -> Vec<HeredocMarker> {
+    let mut markers = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut word_start = true;
+    let mut index = 0;
+    while let Some(c) = command[index..].chars().next() {
+        let was_word_start = word_start;
+        word_start = false;
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quote != Some('\'') {
+            escaped = true;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+        } else if c.is_whitespace() {
+            word_start = true;
+        } else if c == '<' && was_word_start {
+            if let Some((marker, length)) = parse_heredoc_prefix(&command[index..]) {
+                markers.push(marker);
+                index += length;
+                continue;
+            }
+        }
+        index += c.len_utf8();
+    }
+    markers
+}
+
+/// Heredocs whose bodies follow an instruction: any in a shell form RUN, and
+/// heredoc sources of COPY and ADD
+fn heredoc_markers(kind: &InstructionKind) -> Vec<HeredocMarker> {
+    match kind {
+        InstructionKind::Run(CommandForm::Shell(command)) => find_heredoc_markers(command),
+        InstructionKind::Copy { sources, .. } | InstructionKind::Add { sources, .. } => sources
+            .iter()
+            .filter_map(|source| parse_heredoc_marker(source))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Splits leading `--flag[=value]` words off an instruction's arguments.
+/// A bare `--` ends the flags.
+fn split_flags(args: &str) -> (Vec<Flag>, &str) {
+    let mut flags = Vec::new();
+    let mut rest = args;
+    while let Some(word_rest) = rest.strip_prefix("--") {
+        let (word, remaining) = match word_rest.split_once(char::is_whitespace) {
+            Some((word, remaining)) => (word, remaining.trim_start()),
+            None => (word_rest, &word_rest[word_rest.len()..]),
+        };
+        rest = remaining;
+        if word.is_empty() {
+            break;
+        }
+        let flag = match word.split_once('=') {
+            Some((name, value)) => Flag {
+                name: name.to_string(),
+                value: Some(value.to_string()),
+                offset: column(args, value),
+            },
+            None => Flag {
+                name: word.to_string(),
+                value: None,
+                offset: column(args, word) - 2,
+            },
+        };
+        flags.push(flag);
+    }
+    (flags, rest)
+}
+
+/// Splits ENV or LABEL arguments into `name=value` pairs, or reads the
+/// legacy `NAME value` form, where the value is the rest of the line.
+/// Words are split on whitespace outside quotes, and values keep their
+/// quotes and escapes for expansion to process.
+fn parse_name_values<'a>(
+    keyword: &str,
+    args: &'a str,
+    escape: char,
+) -> Result<Vec<(String, &'a str)>, String> {
+    let words = split_words(args, escape);
+    let Some(first) = words.first() else {
+        return Err(format!("{} requires at least one argument", keyword));
+    };
+    if !first.contains('=') {
+        let value = args[first.len()..].trim();
+        if value.is_empty() {
+            return Err(format!("{} requires a value for {}", keyword, first));
+        }
+        return Ok(vec![(unquote(first), value)]);
+    }
+    words
+        .iter()
+        .map(|word| match word.split_once('=') {
+            Some(("", _)) => Err(format!("{} names can not be blank: {}", keyword, word)),
+            Some((name, value)) => Ok((unquote(name), value)),
+            None => Err(format!(
+                "{} expects name=value pairs, but found {}",
+                keyword, word
+            )),
+        })
+        .collect()
+}
+
+/// Splits on whitespace that is not quoted or escaped
+fn split_words(args: &str, escape: char) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in args.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == escape && quote != Some('\'') {
+            escaped = true;
+        } else if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+        } else if c.is_whitespace() {
+            if let Some(start) = start.take() {
+                words.push(&args[start..index]);
+            }
+            continue;
+        }
+        start.get_or_insert(index);
+    }
+    if let Some(start) = start {
+        words.push(&args[start..]);
+    }
+    words
+}
+
+/// Removes the quotes around a LABEL name such as `"com.example.vendor"`
+fn unquote(name: &str) -> String {
+    for quote in ['"', '\''] {
+        if let Some(inner) = name
+            .strip_prefix(quote)
+            .and_then(|name| name.strip_suffix(quote))
+        {
+            return inner.to_string();
+        }
+    }
+    name.to_string()
+}
+
+/// Treats arguments that form a JSON array of strings as exec form, and
+/// anything else as a shell command
+fn parse_command_form(args: &str) -> CommandForm {
+    match parse_json_array(args) {
+        Some(argv) => CommandForm::Exec(argv),
+        None => CommandForm::Shell(args.to_string()),
+    }
+}
+
+fn parse_json_array(args: &str) -> Option<Vec<String>> {
+    if !args.starts_with('[') {
+        return None;
+    }
+    serde_json::from_str(args).ok()
+}
@@ -0,0 +1,1046 @@
+use crate::archive;
+use crate::checksum::Checksum;
+use crate::context::BuildContext;
+use crate::error::{Error, FailedStep};
+use crate::executor::{Context, CopyOptions, Destination, Executor};
+use crate::expand::{self, SubstitutionError};
+use crate::git::GitSource;
+use crate::parser::{self, CommandForm, Heredoc, Instruction, InstructionKind, Stage};
+use crate::{Options, Report};
+use colored::*;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+
+/// A `$NAME` or `${NAME` reference, for the --docker-compat checks
+static VARIABLE_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{?([A-Za-z_][A-Za-z0-9_]*)").unwrap());
+
+/// Flags each instruction understands; any others are reported in debug output
+fn supported_flags(kind: &InstructionKind) -> &'static [&'static str] {
+    match kind {
+        InstructionKind::Copy { .. } => &["chmod", "chown", "from"],
+        InstructionKind::Add { .. } => &["chmod", "chown", "checksum", "keep-git-dir"],
+        _ => &[],
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn multiple_sources_error(dest: &str) -> io::Error {
+    invalid_input(format!(
+        "when copying more than one source, the destination must be a directory ending in /: {}",
+        dest
+    ))
+}
+
+/// Whether a COPY or ADD destination names a directory: it ends in `/`, is
+/// `.`, or is an existing directory
+fn is_dir_dest(dest: &str, path: &Path) -> bool {
+    dest.ends_with('/') || dest == "." || dest.ends_with("/.") || path.is_dir()
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Finds the numeric id for a user or group name in an `/etc/passwd` style file
+fn lookup_id(database: &str, name: &str) -> io::Result<u32> {
+    let contents = fs::read_to_string(database)?;
+    contents
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[0] == name)
+        .and_then(|fields| fields[2].parse().ok())
+        .ok_or_else(|| invalid_input(format!("unable to find {} in {}", name, database)))
+}
+
+/// Parses a `--chown` value: `user`, `user:group`, or their numeric ids.
+/// Without a group, the group id is the same number as the user id.
+fn parse_chown(spec: &str) -> io::Result<(u32, u32)> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let uid = match user.parse() {
+        Ok(uid) => uid,
+        Err(_) => lookup_id("/etc/passwd", user)?,
+    };
+    let gid = match group {
+        Some(group) => match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => lookup_id("/etc/group", group)?,
+        },
+        None => uid,
+    };
+    Ok((uid, gid))
+}
+
+/// The WORKDIR and variables a stage ends with, which stages built on it start from
+#[derive(Debug, Clone)]
+struct Scope {
+    /// The stage's name, or its index when it has none
+    label: String,
+    /// Directory absolute paths in the stage are relative to
+    root: PathBuf,
+    workdir: PathBuf,
+    /// ENV values, which unlike ARGs carry over to stages built on this one
+    env: BTreeMap<String, String>,
+}
+
+/// Executes instructions one at a time, tracking WORKDIR and variables
+pub(crate) struct Runner<'a> {
+    options: &'a Options,
+    executor: &'a mut dyn Executor,
+    workdir: PathBuf,
+    /// Root directory of the current stage
+    root: PathBuf,
+    /// Where the run started, and where stages based on an image start
+    start_workdir: PathBuf,
+    /// ARGs declared before the first FROM, which stages only see by declaring them again
+    global_args: BTreeMap<String, String>,
+    /// ENV values set before the first FROM
+    global_env: BTreeMap<String, String>,
+    /// Index and label of the stage being run
+    stage: Option<(usize, String)>,
+    finished_stages: BTreeMap<usize, Scope>,
+    /// Root of the build context local COPY and ADD sources come from
+    context_root: PathBuf,
+    /// Loaded on first use, along with its `.dockerignore`
+    build_context: Option<BuildContext>,
+    /// Values set by ENV in the current stage
+    env: BTreeMap<String, String>,
+    /// Values of ARGs declared in the current stage
+    args: BTreeMap<String, String>,
+    /// Escape character set by the `# escape=` directive
+    escape: char,
+    /// First line of the instruction being run, for substitution errors
+    line: usize,
+    /// Values given with `--build-arg` and `--build-arg-file`, or none for a
+    /// name given without one
+    build_args: BTreeMap<String, Option<String>>,
+    /// Build args some ARG has used
+    used_build_args: BTreeSet<String>,
+    failures: Vec<FailedStep>,
+    instructions: usize,
+}
+
+impl<'a> Runner<'a> {
+    pub(crate) fn new(
+        options: &'a Options,
+        build_args: BTreeMap<String, Option<String>>,
+        escape: char,
+        executor: &'a mut dyn Executor,
+    ) -> io::Result<Self> {
+        let workdir = match &options.workdir {
+            Some(workdir) => workdir.clone(),
+            None => env::current_dir()?,
+        };
+        let context_root = match &options.context {
+            Some(context) => context.clone(),
+            None => match options.dockerfile.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+        };
+        Ok(Runner {
+            options,
+            executor,
+            start_workdir: workdir.clone(),
+            workdir,
+            root: PathBuf::from("/"),
+            global_args: BTreeMap::new(),
+            global_env: BTreeMap::new(),
+            stage: None,
+            finished_stages: BTreeMap::new(),
+            context_root,
+            build_context: None,
+            env: BTreeMap::new(),
+            args: BTreeMap::new(),
+            escape,
+            line: 0,
+            build_args,
+            used_build_args: BTreeSet::new(),
+            failures: Vec::new(),
+            instructions: 0,
+        })
+    }
+
+    pub(crate) fn finish(self) -> Report {
+        let unused: Vec<&str> = self
+            .build_args
+            .keys()
+            .filter(|name| !self.used_build_args.contains(*name))
+            .map(String::as_str)
+            .collect();
+        if !unused.is_empty() {
+            eprintln!(
+                "{} {}",
+                "Warning:".yellow().bold(),
+                format!("Build args not used by any ARG: {}", unused.join(", ")).bright_white()
+            );
+        }
+        Report {
+            instructions: self.instructions,
+            failures: self.failures,
+        }
+    }
+
+    pub(crate) fn debug(&self, message: ColoredString) {
+        if self.options.debug {
+            println!("{} {}", "DEBUG:".bright_blue().bold(), message);
+        }
+    }
+
+    /// Prints a warning about an instruction that Docker would treat differently
+    pub(crate) fn warn_compat(&self, instruction: &Instruction, message: String) {
+        if self.options.docker_compat {
+            eprintln!(
+                "{} {}: {}",
+                "Warning:".yellow().bold(),
+                instruction.line_range(),
+                message.bright_white()
+            );
+        }
+    }
+
+    /// Looks up a variable in scope, falling back to the process environment,
+    /// which stands in for the environment of the base image. ENV takes
+    /// precedence over an ARG of the same name, as in Docker.
+    fn lookup(&self, name: &str) -> Option<String> {
+        self.env
+            .get(name)
+            .or_else(|| self.args.get(name))
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
+
+    /// The variables in scope, as the environment of commands
+    fn variables(&self) -> BTreeMap<String, String> {
+        let mut variables = self.args.clone();
+        variables.extend(self.env.clone());
+        variables
+    }
+
+    /// Points out variables an instruction uses that only resolve because of
+    /// dfrun's looser scoping: ARGs declared before the first FROM but not in
+    /// the stage, and, where dfrun expands them itself, host environment variables
+    fn check_compat(&self, instruction: &Instruction) {
+        if !self.options.docker_compat {
+            return;
+        }
+        let expanded_by_dfrun = matches!(
+            instruction.kind,
+            InstructionKind::Workdir(_)
+                | InstructionKind::Copy { .. }
+                | InstructionKind::Add { .. }
+                | InstructionKind::Env(_)
+                | InstructionKind::Label(_)
+        );
+        let mut reported = BTreeSet::new();
+        for caps in VARIABLE_REFERENCE.captures_iter(&instruction.original) {
+            let name = caps.get(1).unwrap().as_str();
+            if self.env.contains_key(name) || self.args.contains_key(name) || !reported.insert(name)
+            {
+                continue;
+            }
+            if self.stage.is_some() && self.global_args.contains_key(name) {
+                self.warn_compat(
+                    instruction,
+                    format!(
+                        "{} is declared before the first FROM, so Docker only uses it here after `ARG {}` in this stage",
+                        name, name
+                    ),
+                );
+            } else if expanded_by_dfrun && env::var_os(name).is_some() {
+                self.warn_compat(
+                    instruction,
+                    format!(
+                        "{} is not declared with ARG or ENV; dfrun takes it from the host environment, where Docker would expand it to an empty string",
+                        name
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Expands variables in an argument of the current instruction with
+    /// Docker's substitution grammar (see [`expand::expand`]). Undefined
+    /// variables become empty strings with a warning, or fail with `strict`.
+    /// `start` is where the argument begins in the instruction, for errors.
+    fn expand(&self, s: &str, start: usize) -> Result<String, SubstitutionError> {
+        self.substitute(s, start, false)
+    }
+
+    /// Expands an ENV, LABEL or ARG value, removing its quotes
+    fn expand_value(&self, s: &str, start: usize) -> Result<String, SubstitutionError> {
+        self.substitute(s, start, true)
+    }
+
+    /// Expands the value of a flag of `instruction`, if it is given
+    fn expand_flag(
+        &self,
+        instruction: &Instruction,
+        name: &str,
+    ) -> Result<Option<String>, SubstitutionError> {
+        instruction
+            .flags
+            .iter()
+            .find(|flag| flag.name == name)
+            .map(|flag| self.expand(flag.value.as_deref().unwrap_or(""), flag.offset))
+            .transpose()
+    }
+
+    fn substitute(&self, s: &str, start: usize, quotes: bool) -> Result<String, SubstitutionError> {
+        let error = |offset: usize, message: String| SubstitutionError {
+            line: self.line,
+            column: start + offset + 1,
+            message,
+        };
+        let lookup = |name: &str| self.lookup(name);
+        let expanded = if quotes {
+            expand::expand_value(s, self.escape, &lookup)
+        } else {
+            expand::expand(s, self.escape, &lookup)
+        }
+        .map_err(|invalid| error(invalid.offset, invalid.message))?;
+        for undefined in expanded.undefined {
+            let e = error(
+                undefined.offset,
+                format!("{} is not defined", undefined.name),
+            );
+            if self.options.strict {
+                return Err(e);
+            }
+            eprintln!(
+                "{} {}",
+                "Warning:".yellow().bold(),
+                format!("{}; using an empty string", e).bright_white()
+            );
+        }
+        Ok(expanded.value)
+    }
+
+    /// Turns the outcome of an executor call into a step failure when it did not succeed.
+    /// Without `keep_going` the failure stops the run.
+    fn check(&mut self, instruction: &Instruction, result: io::Result<i32>) -> Result<(), Error> {
+        let (code, reason) = match result {
+            Ok(0) => return Ok(()),
+            Ok(code) => (code, format!("exit status: {}", code)),
+            Err(e) => (1, format!("error: {}", e)),
+        };
+        let failure = FailedStep {
+            start_line: instruction.start_line,
+            end_line: instruction.end_line,
+            instruction: instruction.original.clone(),
+            code,
+            reason,
+        };
+        if !self.options.keep_going {
+            return Err(Error::StepFailed(failure));
+        }
+        eprintln!(
+            "{} {}",
+            "Error:".red().bold(),
+            failure.to_string().bright_white()
+        );
+        self.failures.push(failure);
+        Ok(())
+    }
+
+    /// Switches to a new stage's scope. A stage built on an earlier stage
+    /// inherits its root, WORKDIR and ENV values; one built on an image starts
+    /// in a root of its own under `stage_dir` if that is set, or else where
+    /// the run started. As in Docker, ARGs never carry over: ARGs declared
+    /// before the first FROM are only used in FROM lines until a stage
+    /// declares them again.
+    pub(crate) fn start_stage(&mut self, stage: &Stage) -> Result<(), Error> {
+        self.instructions += 1;
+        self.line = stage.from.start_line;
+        self.executor.start_instruction(stage.from);
+        self.debug(
+            format!(
+                "Processing {}: {}",
+                stage.from.line_range(),
+                stage.from.original
+            )
+            .bright_white(),
+        );
+        match self.stage.take() {
+            Some((index, label)) => {
+                let scope = Scope {
+                    label,
+                    root: self.root.clone(),
+                    workdir: self.workdir.clone(),
+                    env: self.env.clone(),
+                };
+                self.finished_stages.insert(index, scope);
+            }
+            None => {
+                self.global_args = std::mem::take(&mut self.args);
+                self.global_env = self.env.clone();
+            }
+        }
+        // FROM only sees the ARGs and ENV from before the first FROM
+        self.args = self.global_args.clone();
+        self.env = self.global_env.clone();
+        let image = match &stage.from.kind {
+            InstructionKind::From { image, .. } => self.expand(image, stage.from.offsets[0])?,
+            _ => String::new(),
+        };
+        let label = stage
+            .name
+            .clone()
+            .unwrap_or_else(|| stage.index.to_string());
+        let base = stage
+            .base
+            .and_then(|base| self.finished_stages.get(&base))
+            .cloned();
+        let (root, workdir, env) = match (base, &self.options.stage_dir) {
+            (Some(base), _) => (base.root, base.workdir, base.env),
+            (None, Some(stage_dir)) => {
+                let root = stage_dir.join(&label);
+                self.executor
+                    .create_dir_all(&root)
+                    .map_err(|e| Error::Workdir(root.clone(), e))?;
+                (root.clone(), root, self.global_env.clone())
+            }
+            (None, None) => (
+                PathBuf::from("/"),
+                self.start_workdir.clone(),
+                self.global_env.clone(),
+            ),
+        };
+        self.debug(
+            format!(
+                "Action: Starting stage {} from {} in {} (root {})",
+                label,
+                image,
+                workdir.display(),
+                root.display()
+            )
+            .cyan(),
+        );
+        self.root = root;
+        self.workdir = workdir;
+        self.env = env;
+        self.args.clear();
+        self.stage = Some((stage.index, label));
+        Ok(())
+    }
+
+    /// Resolves a path named in the current stage: absolute paths are inside
+    /// the stage's root, relative ones are relative to WORKDIR, and neither
+    /// can leave the root
+    fn resolve(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        let mut resolved = if path.has_root() {
+            self.root.clone()
+        } else {
+            self.workdir.clone()
+        };
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                // `..` never leaves the root, as in a container
+                Component::ParentDir if resolved != self.root => {
+                    resolved.pop();
+                }
+                _ => {}
+            }
+        }
+        resolved
+    }
+
+    /// The earlier stage `COPY --from` names
+    fn stage_scope(&self, reference: &str) -> io::Result<&Scope> {
+        let reference_index = reference.parse::<usize>().ok();
+        self.finished_stages
+            .iter()
+            .find(|(index, scope)| {
+                scope.label.eq_ignore_ascii_case(reference) || Some(**index) == reference_index
+            })
+            .map(|(_, scope)| scope)
+            .ok_or_else(|| {
+                invalid_input(format!(
+                    "COPY --from={} does not name an earlier build stage; dfrun cannot copy from images",
+                    reference
+                ))
+            })
+    }
+
+    /// The root of the earlier stage `COPY --from` names, as a source to copy from
+    fn stage_source(&self, reference: &str) -> io::Result<BuildContext> {
+        let scope = self.stage_scope(reference)?;
+        Ok(BuildContext::unfiltered(
+            &scope.root,
+            format!("stage {} ({})", scope.label, scope.root.display()),
+        ))
+    }
+
+    pub(crate) fn execute(&mut self, instruction: &Instruction) -> Result<(), Error> {
+        self.instructions += 1;
+        self.line = instruction.start_line;
+        self.executor.start_instruction(instruction);
+        self.debug(
+            format!(
+                "Processing {}: {}",
+                instruction.line_range(),
+                instruction.original
+            )
+            .bright_white(),
+        );
+        let supported = supported_flags(&instruction.kind);
+        for flag in &instruction.flags {
+            if !supported.contains(&flag.name.as_str()) {
+                self.debug(format!("Action: Ignoring unsupported flag {}", flag).red());
+            }
+        }
+        self.check_compat(instruction);
+
+        match &instruction.kind {
+            InstructionKind::Workdir(raw_dir) => {
+                let dir = self.expand(raw_dir, instruction.offsets[0])?;
+                let new_workdir = self.resolve(&dir);
+                self.debug(
+                    format!(
+                        "Action: Changing WORKDIR to {} (expanded from {})",
+                        new_workdir.display(),
+                        raw_dir
+                    )
+                    .cyan(),
+                );
+                self.executor
+                    .create_dir_all(&new_workdir)
+                    .map_err(|e| Error::Workdir(new_workdir.clone(), e))?;
+                self.workdir = new_workdir;
+            }
+            InstructionKind::Run(form) => {
+                let result = self.run_command(form, &instruction.heredocs);
+                self.check(instruction, result)?;
+            }
+            InstructionKind::Copy { sources, dest } => {
+                let result = self.copy(instruction, sources, dest).map(|()| 0);
+                self.check(instruction, result)?;
+            }
+            InstructionKind::Add { sources, dest } => {
+                let result = self.add(instruction, sources, dest).map(|()| 0);
+                self.check(instruction, result)?;
+            }
+            InstructionKind::Env(pairs) => {
+                // As in Docker, every value sees the variables from before the instruction
+                let mut values = Vec::new();
+                for ((key, raw_value), &start) in pairs.iter().zip(&instruction.offsets) {
+                    values.push((key, raw_value, self.expand_value(raw_value, start)?));
+                }
+                for (key, raw_value, value) in values {
+                    self.debug(
+                        format!(
+                            "Action: Setting environment variable: {}={} (expanded from {})",
+                            key, value, raw_value
+                        )
+                        .magenta(),
+                    );
+                    self.env.insert(key.clone(), value);
+                }
+            }
+            InstructionKind::Label(pairs) => {
+                // Labels describe an image, so there is nothing to set
+                for ((key, raw_value), &start) in pairs.iter().zip(&instruction.offsets) {
+                    let value = self.expand_value(raw_value, start)?;
+                    self.debug(format!("Action: Ignoring LABEL {}={}", key, value).red());
+                }
+            }
+            InstructionKind::Arg { name, default } => {
+                let default = default
+                    .as_deref()
+                    .map(|default| self.expand_value(default, instruction.offsets[0]))
+                    .transpose()?;
+                let value = self.resolve_arg(instruction, name, default)?;
+                self.debug(format!("Action: Setting ARG variable: {}={}", name, value).magenta());
+                self.args.insert(name.clone(), value);
+            }
+            _ => {
+                self.debug("Action: Ignoring unsupported instruction".red());
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a RUN, CMD or ENTRYPOINT command in the current WORKDIR. Exec form
+    /// is started without a shell and, as in Docker, without variable expansion.
+    ///
+    /// A command that is a single heredoc runs the heredoc as a script, using
+    /// its `#!` line as the interpreter if it has one. Otherwise heredoc bodies
+    /// are appended to the command for the shell to feed to it.
+    fn run_command(&mut self, form: &CommandForm, heredocs: &[Heredoc]) -> io::Result<i32> {
+        let env = self.variables();
+        let context = Context {
+            workdir: &self.workdir,
+            env: &env,
+        };
+        match form {
+            CommandForm::Shell(command) if !heredocs.is_empty() => {
+                if heredocs.len() == 1 && parser::parse_heredoc_marker(command.trim()).is_some() {
+                    let script = &heredocs[0].content;
+                    self.debug(
+                        format!(
+                            "Action: Executing heredoc script in {}:\n{}",
+                            self.workdir.display(),
+                            script
+                        )
+                        .green(),
+                    );
+                    if script.starts_with("#!") {
+                        self.executor.run_script(script, &context)
+                    } else {
+                        self.executor.run_shell(script, &context)
+                    }
+                } else {
+                    let mut script = format!("{}\n", command);
+                    for heredoc in heredocs {
+                        script.push_str(&heredoc.content);
+                        script.push_str(&heredoc.name);
+                        script.push('\n');
+                    }
+                    self.debug(
+                        format!(
+                            "Action: Executing command with heredocs in {}:\n{}",
+                            self.workdir.display(),
+                            script
+                        )
+                        .green(),
+                    );
+                    self.executor.run_shell(&script, &context)
+                }
+            }
+            CommandForm::Shell(command) => {
+                self.debug(
+                    format!(
+                        "Action: Executing command in {}: {}",
+                        self.workdir.display(),
+                        command
+                    )
+                    .green(),
+                );
+                self.executor.run_shell(command, &context)
+            }
+            CommandForm::Exec(argv) => {
+                self.debug(
+                    format!(
+                        "Action: Executing {:?} in {} without a shell",
+                        argv,
+                        self.workdir.display()
+                    )
+                    .green(),
+                );
+                self.executor.run_exec(argv, &context)
+            }
+        }
+    }
+
+    /// Reads `--chmod` and `--chown` for COPY and ADD
+    fn copy_options(&self, instruction: &Instruction) -> io::Result<CopyOptions> {
+        let mode = match self.expand_flag(instruction, "chmod")? {
+            Some(value) => {
+                let mode = u32::from_str_radix(&value, 8)
+                    .map_err(|_| invalid_input(format!("invalid --chmod value: {}", value)))?;
+                Some(mode)
+            }
+            None => None,
+        };
+        let owner = match self.expand_flag(instruction, "chown")? {
+            Some(value) => Some(parse_chown(&value)?),
+            None => None,
+        };
+        Ok(CopyOptions { mode, owner })
+    }
+
+    /// Copies COPY sources, which are heredocs or paths in the build context,
+    /// to `dest`
+    fn copy(
+        &mut self,
+        instruction: &Instruction,
+        sources: &[String],
+        dest: &str,
+    ) -> io::Result<()> {
+        let options = self.copy_options(instruction)?;
+        let dest = self.expand(dest, instruction.offsets[sources.len()])?;
+        let mut local_sources = Vec::new();
+        for (source, &start) in sources.iter().zip(&instruction.offsets) {
+            match instruction.heredoc_source(source) {
+                Some(heredoc) => self.write_heredoc(heredoc, &dest, &options)?,
+                None => local_sources.push(self.expand(source, start)?),
+            }
+        }
+        if !local_sources.is_empty() {
+            let context = match self.expand_flag(instruction, "from")? {
+                // A plan never ran the earlier stage, so its files are not
+                // there to resolve
+                Some(reference) if self.executor.dry_run() => {
+                    let label = self.stage_scope(&reference)?.label.clone();
+                    let dest_path = self.resolve(&dest);
+                    for source in &local_sources {
+                        let from = PathBuf::from(format!("{}:{}", label, source));
+                        self.executor.copy_file(&from, &dest_path, &options)?;
+                    }
+                    return Ok(());
+                }
+                Some(reference) => self.stage_source(&reference)?,
+                None => self.build_context()?,
+            };
+            let multiple_sources = sources.len() > 1;
+            self.copy_from_context(
+                &context,
+                &local_sources,
+                &dest,
+                multiple_sources,
+                &options,
+                false,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Carries out ADD: like COPY, except that URLs are downloaded and local
+    /// tar archives are unpacked into the destination
+    fn add(&mut self, instruction: &Instruction, sources: &[String], dest: &str) -> io::Result<()> {
+        let options = self.copy_options(instruction)?;
+        let checksum = match self.expand_flag(instruction, "checksum")? {
+            Some(value) => Some(value.parse::<Checksum>().map_err(invalid_input)?),
+            None => None,
+        };
+        let mut has_non_http_source = false;
+        for (source, &start) in sources.iter().zip(&instruction.offsets) {
+            let source = self.expand(source, start)?;
+            has_non_http_source |= instruction.heredoc_source(&source).is_none()
+                && (!is_url(&source) || GitSource::parse(&source).is_some());
+        }
+        if checksum.is_some() && has_non_http_source {
+            return Err(invalid_input(
+                "--checksum is only supported for HTTP(S) sources".to_string(),
+            ));
+        }
+        let keep_git_dir = match instruction.flag("keep-git-dir") {
+            None | Some("false") => false,
+            Some("" | "true") => true,
+            Some(other) => {
+                return Err(invalid_input(format!(
+                    "invalid --keep-git-dir value: {}",
+                    other
+                )))
+            }
+        };
+        let dest = self.expand(dest, instruction.offsets[sources.len()])?;
+        let multiple_sources = sources.len() > 1;
+        let mut local_sources = Vec::new();
+        for (source, &start) in sources.iter().zip(&instruction.offsets) {
+            if let Some(heredoc) = instruction.heredoc_source(source) {
+                self.write_heredoc(heredoc, &dest, &options)?;
+                continue;
+            }
+            let source = self.expand(source, start)?;
+            if let Some(repository) = GitSource::parse(&source) {
+                self.fetch_git(&repository, &dest, keep_git_dir)?;
+                continue;
+            }
+            if !is_url(&source) {
+                local_sources.push(source);
+                continue;
+            }
+            self.download(
+                &source,
+                &dest,
+                multiple_sources,
+                checksum.as_ref(),
+                &options,
+            )?;
+        }
+        if !local_sources.is_empty() {
+            let context = self.build_context()?;
+            self.copy_from_context(
+                &context,
+                &local_sources,
+                &dest,
+                multiple_sources,
+                &options,
+                true,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Clones a git repository into the directory `dest`
+    fn fetch_git(
+        &mut self,
+        repository: &GitSource,
+        dest: &str,
+        keep_git_dir: bool,
+    ) -> io::Result<()> {
+        let dest_path = self.resolve(dest);
+        self.debug(
+            format!(
+                "Action: Cloning {} (ref {}) into {}",
+                repository.url,
+                repository.reference.as_deref().unwrap_or("HEAD"),
+                dest_path.display()
+            )
+            .cyan(),
+        );
+        self.executor.create_dir_all(&dest_path)?;
+        let env = self.variables();
+        let context = Context {
+            workdir: &self.workdir,
+            env: &env,
+        };
+        self.executor
+            .fetch_git(repository, &dest_path, keep_git_dir, &context)
+    }
+
+    /// Downloads a URL to `dest`, or into it when it is a directory
+    fn download(
+        &mut self,
+        url: &str,
+        dest: &str,
+        multiple_sources: bool,
+        checksum: Option<&Checksum>,
+        options: &CopyOptions,
+    ) -> io::Result<()> {
+        let dest_path = self.resolve(dest);
+        let target = if is_dir_dest(dest, &dest_path) {
+            self.executor.create_dir_all(&dest_path)?;
+            Destination::Dir(&dest_path)
+        } else if multiple_sources {
+            return Err(multiple_sources_error(dest));
+        } else {
+            if let Some(parent) = dest_path.parent() {
+                self.executor.create_dir_all(parent)?;
+            }
+            Destination::File(&dest_path)
+        };
+        self.debug(format!("Action: Downloading {} to {}", url, dest_path.display()).cyan());
+        let env = self.variables();
+        let context = Context {
+            workdir: &self.workdir,
+            env: &env,
+        };
+        let path = self
+            .executor
+            .download(url, target, checksum, options, &context)?;
+        self.debug(format!("Action: Saved {} as {}", url, path.display()).cyan());
+        Ok(())
+    }
+
+    fn build_context(&mut self) -> io::Result<BuildContext> {
+        if self.build_context.is_none() {
+            self.debug(
+                format!(
+                    "Action: Loading build context from {}",
+                    self.context_root.display()
+                )
+                .cyan(),
+            );
+            self.build_context = Some(BuildContext::open(&self.context_root)?);
+        }
+        Ok(self.build_context.clone().unwrap())
+    }
+
+    /// Copies paths from the build context (or a stage) the way Docker does:
+    /// wildcards may match several paths, a directory's contents (not the
+    /// directory itself) are copied, and a directory destination receives
+    /// files under their own names. With `extract_archives`, tar archives are
+    /// unpacked into the destination instead of being copied.
+    fn copy_from_context(
+        &mut self,
+        context: &BuildContext,
+        sources: &[String],
+        dest: &str,
+        multiple_sources: bool,
+        options: &CopyOptions,
+        extract_archives: bool,
+    ) -> io::Result<()> {
+        let mut paths = Vec::new();
+        for source in sources {
+            paths.extend(context.resolve(source)?);
+        }
+
+        let dest_path = self.resolve(dest);
+        let dest_is_dir = is_dir_dest(dest, &dest_path);
+        if (multiple_sources || paths.len() > 1) && !dest_is_dir {
+            return Err(multiple_sources_error(dest));
+        }
+
+        for path in paths {
+            if path.is_dir() {
+                self.debug(
+                    format!(
+                        "Action: Copying contents of {} to {}",
+                        path.display(),
+                        dest_path.display()
+                    )
+                    .cyan(),
+                );
+                self.executor.create_dir_all(&dest_path)?;
+                for entry in context.walk(&path)? {
+                    let target = dest_path.join(&entry.relative);
+                    if entry.is_dir {
+                        self.executor.create_dir_all(&target)?;
+                    } else {
+                        self.executor
+                            .copy_file(&path.join(&entry.relative), &target, options)?;
+                    }
+                }
+            } else if extract_archives && archive::detect(&path)?.is_some() {
+                self.debug(
+                    format!(
+                        "Action: Extracting {} to {}",
+                        path.display(),
+                        dest_path.display()
+                    )
+                    .cyan(),
+                );
+                self.executor.extract_archive(&path, &dest_path)?;
+            } else {
+                let target = match path.file_name() {
+                    Some(name) if dest_is_dir => dest_path.join(name),
+                    _ => dest_path.clone(),
+                };
+                self.debug(
+                    format!("Action: Copying {} to {}", path.display(), target.display()).cyan(),
+                );
+                if let Some(parent) = target.parent() {
+                    self.executor.create_dir_all(parent)?;
+                }
+                self.executor.copy_file(&path, &target, options)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a heredoc source of COPY or ADD to its (expanded) destination. A
+    /// directory destination gets a file named after the heredoc.
+    fn write_heredoc(
+        &mut self,
+        heredoc: &Heredoc,
+        dest: &str,
+        options: &CopyOptions,
+    ) -> io::Result<()> {
+        let mut path = self.resolve(dest);
+        if is_dir_dest(dest, &path) {
+            path.push(&heredoc.name);
+        }
+        let content = if heredoc.expand {
+            // Heredoc bodies are not part of the instruction's line, so
+            // errors in them point at its start
+            self.expand(&heredoc.content, 0)?
+        } else {
+            heredoc.content.clone()
+        };
+        self.debug(
+            format!(
+                "Action: Writing heredoc {} to {}",
+                heredoc.name,
+                path.display()
+            )
+            .cyan(),
+        );
+        if let Some(parent) = path.parent() {
+            self.executor.create_dir_all(parent)?;
+        }
+        self.executor.write_file(&path, content.as_bytes(), options)
+    }
+
+    /// Picks an ARG's value from user input, the environment or its default.
+    /// An ARG declared again without a default keeps the value it already
+    /// has, either in this stage or from before the first FROM.
+    fn resolve_arg(
+        &mut self,
+        instruction: &Instruction,
+        name: &str,
+        default_value: Option<String>,
+    ) -> Result<String, Error> {
+        if default_value.is_none() {
+            let declared = self.args.get(name).or_else(|| match self.stage {
+                Some(_) => self.global_args.get(name),
+                None => None,
+            });
+            if let Some(value) = declared {
+                self.debug(format!("Action: Using declared value: {}", value).green());
+                return Ok(value.clone());
+            }
+        }
+        if let Some(value) = self.build_args.get(name) {
+            self.used_build_args.insert(name.to_string());
+            if let Some(value) = value {
+                self.debug(format!("Action: Using build arg value: {}", value).green());
+                return Ok(value.clone());
+            }
+        }
+        let env_value = env::var(name).ok();
+        let is_interactive = self.options.interactive;
+        if env_value.is_some() {
+            self.warn_compat(
+                instruction,
+                format!(
+                    "ARG {} takes its value from the host environment; Docker needs --build-arg {}=...",
+                    name, name
+                ),
+            );
+        }
+
+        self.debug(
+            format!(
+                "Action: Found ARG: {} (default: {:?}, env: {:?}, interactive: {})",
+                name, default_value, env_value, is_interactive
+            )
+            .yellow(),
+        );
+
+        if is_interactive {
+            // Interactive mode: prompt for input
+            let prompt_default = default_value.as_ref().or(env_value.as_ref());
+            print!(
+                "Enter value for ARG {}{}: ",
+                name,
+                prompt_default.map_or("".to_string(), |v| format!(" (default: {})", v))
+            );
+            io::stdout().flush()?;
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            let input = input.trim();
+            if input.is_empty() {
+                match default_value.or(env_value) {
+                    Some(val) => {
+                        self.debug(format!("Action: Using default/env value: {}", val).green());
+                        Ok(val)
+                    }
+                    None => Err(Error::MissingArg {
+                        name: name.to_string(),
+                        interactive: true,
+                    }),
+                }
+            } else {
+                self.debug(format!("Action: Using provided value: {}", input).green());
+                Ok(input.to_string())
+            }
+        } else if let Some(val) = env_value {
+            // Non-interactive mode: use env value, then default, or error
+            self.debug(format!("Action: Using environment value: {}", val).green());
+            Ok(val)
+        } else if let Some(val) = default_value {
+            self.debug(format!("Action: Using default value: {}", val).green());
+            Ok(val)
+        } else {
+            Err(Error::MissingArg {
+                name: name.to_string(),
+                interactive: false,
+            })
+        }
+    }
+}
@@ -1,11 +1,15 @@
+use crate::parser::{self, CommandForm, InstructionKind};
+use crate::{Checksum, Context, CopyOptions, Destination, Error, Executor, GitSource, Options};
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::thread;
 use std::time::Duration;
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use super::*;
 
@@ -73,13 +77,184 @@ mod tests {
         }
     }
 
+    /// Starts `cargo` for running the dfrun binary. The package variables Cargo
+    /// sets for tests are removed so dependency build scripts that watch them
+    /// are not rebuilt on every run.
+    fn cargo_command() -> Command {
+        let mut command = Command::new("cargo");
+        for (name, _) in std::env::vars_os() {
+            let name = name.to_string_lossy();
+            if [
+                "CARGO_PKG_",
+                "CARGO_MANIFEST_",
+                "CARGO_CRATE_",
+                "CARGO_PRIMARY_",
+            ]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+            {
+                command.env_remove(name.as_ref());
+            }
+        }
+        command
+    }
+
+    /// Serves HTTP responses from a local port, standing in for a remote host.
+    /// `respond` receives the request line and headers and returns the raw response.
+    fn start_http_server<F>(respond: F) -> String
+    where
+        F: Fn(&str) -> Vec<u8> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test server");
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                stream.write_all(&respond(&request)).ok();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    /// Builds a raw HTTP/1.1 response for `start_http_server`
+    fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// Records the actions a run asks for instead of carrying them out
+    #[derive(Default)]
+    struct RecordingExecutor {
+        actions: Vec<String>,
+        /// Exit code returned for shell commands
+        exit_code: i32,
+    }
+
+    impl Executor for RecordingExecutor {
+        fn run_shell(&mut self, command: &str, context: &Context) -> io::Result<i32> {
+            let env: Vec<String> = context
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            self.actions.push(format!(
+                "run {} in {} with {}",
+                command,
+                context.workdir.display(),
+                env.join(",")
+            ));
+            Ok(self.exit_code)
+        }
+
+        fn run_exec(&mut self, argv: &[String], context: &Context) -> io::Result<i32> {
+            self.actions
+                .push(format!("exec {:?} in {}", argv, context.workdir.display()));
+            Ok(self.exit_code)
+        }
+
+        fn run_script(&mut self, script: &str, context: &Context) -> io::Result<i32> {
+            self.actions.push(format!(
+                "script {:?} in {}",
+                script,
+                context.workdir.display()
+            ));
+            Ok(self.exit_code)
+        }
+
+        fn download(
+            &mut self,
+            url: &str,
+            dest: Destination,
+            _checksum: Option<&Checksum>,
+            _options: &CopyOptions,
+            _context: &Context,
+        ) -> io::Result<PathBuf> {
+            let (action, path) = match dest {
+                Destination::File(path) => ("to", path),
+                Destination::Dir(path) => ("into", path),
+            };
+            self.actions
+                .push(format!("download {} {} {}", url, action, path.display()));
+            Ok(path.to_path_buf())
+        }
+
+        fn create_dir_all(&mut self, path: &Path) -> io::Result<()> {
+            self.actions.push(format!("mkdir {}", path.display()));
+            Ok(())
+        }
+
+        fn write_file(
+            &mut self,
+            path: &Path,
+            contents: &[u8],
+            _options: &CopyOptions,
+        ) -> io::Result<()> {
+            self.actions.push(format!(
+                "write {} {:?}",
+                path.display(),
+                String::from_utf8_lossy(contents)
+            ));
+            Ok(())
+        }
+
+        fn copy_file(&mut self, from: &Path, to: &Path, _options: &CopyOptions) -> io::Result<()> {
+            self.actions
+                .push(format!("copy {} to {}", from.display(), to.display()));
+            Ok(())
+        }
+
+        fn fetch_git(
+            &mut self,
+            repository: &GitSource,
+            dest: &Path,
+            keep_git_dir: bool,
+            _context: &Context,
+        ) -> io::Result<()> {
+            self.actions.push(format!(
+                "clone {:?} into {}{}",
+                repository,
+                dest.display(),
+                if keep_git_dir { " with .git" } else { "" }
+            ));
+            Ok(())
+        }
+
+        fn extract_archive(&mut self, archive: &Path, dest: &Path) -> io::Result<()> {
+            self.actions.push(format!(
+                "extract {} to {}",
+                archive.display(),
+                dest.display()
+            ));
+            Ok(())
+        }
+    }
+
     #[test]
     fn test_parse_arg_with_default() {
         let (test_dir, dockerfile_path) =
             create_test_dockerfile("ARG VERSION=1.0.0", "arg_with_default");
         println!("Dockerfile path: {:?}", dockerfile_path);
 
-        let mut child = Command::new("cargo")
+        let mut child = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -108,7 +283,7 @@ mod tests {
         let (test_dir, dockerfile_path) = create_test_dockerfile("ENV TEST_VAR=test_value", "env");
         println!("Dockerfile path: {:?}", dockerfile_path);
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -128,7 +303,7 @@ mod tests {
         let (test_dir, dockerfile_path) = create_test_dockerfile("RUN echo 'test'", "run");
         println!("Dockerfile path: {:?}", dockerfile_path);
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -146,14 +321,25 @@ mod tests {
 
     #[test]
     fn test_parse_add_url() {
-        let (test_dir, dockerfile_path) = create_test_dockerfile(
-            "ADD https://example.com/file.txt ./temp/file.txt",
-            "add_url",
-        );
+        let url = start_http_server(|_| http_response("200 OK", &[], b"downloaded"));
+        let (test_dir, dockerfile_path) = create_test_dockerfile("", "add_url");
+        // Relative destinations resolve against the directory dfrun runs in
+        let dest = test_dir.join("downloads/file.txt");
+        fs::write(
+            &dockerfile_path,
+            format!("ADD {}/file.txt {}", url, dest.display()),
+        )
+        .unwrap();
         println!("Dockerfile path: {:?}", dockerfile_path);
 
-        let output = Command::new("cargo")
-            .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "--no-cache",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+            ])
             .output()
             .expect("Failed to execute command");
 
@@ -163,6 +349,7 @@ mod tests {
         println!("Command stderr: {}", stderr);
 
         assert!(output.status.success());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "downloaded");
 
         // Clean up
         cleanup_test_dir(test_dir);
@@ -178,7 +365,7 @@ RUN pwd"#;
         let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "workdir");
         println!("Dockerfile path: {:?}", dockerfile_path);
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -199,7 +386,49 @@ RUN pwd"#;
             "pwd outputs should be the same: '{}' vs '{}'",
             pwd_outputs[0], pwd_outputs[1]
         );
+        assert!(
+            pwd_outputs[0].ends_with("temp/test_workdir"),
+            "pwd should be the WORKDIR, got: {}",
+            pwd_outputs[0]
+        );
+        assert!(
+            PathBuf::from("temp/test_workdir/new_folder").exists(),
+            "new_folder should be created inside the WORKDIR"
+        );
+
+        fs::remove_dir_all("temp/test_workdir").ok();
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_workdir_relative_and_expansion() {
+        // Test that relative WORKDIRs resolve against the previous one and expand variables
+        let dockerfile_content = r#"ENV SUBDIR=nested
+WORKDIR temp/test_workdir_chain
+WORKDIR $SUBDIR/../inner
+RUN pwd > workdir_test.txt"#;
+
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "workdir_chain");
+
+        let output = cargo_command()
+            .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
+            .output()
+            .expect("Failed to execute command");
+
+        assert!(output.status.success());
+
+        let test_file = PathBuf::from("temp/test_workdir_chain/inner/workdir_test.txt");
+        assert!(test_file.exists(), "workdir_test.txt should exist");
+
+        let content = fs::read_to_string(&test_file).expect("Failed to read workdir_test.txt");
+        assert!(
+            content.trim().ends_with("temp/test_workdir_chain/inner"),
+            "workdir_test.txt should contain the resolved WORKDIR, got: {}",
+            content
+        );
 
+        fs::remove_dir_all("temp/test_workdir_chain").ok();
         cleanup_test_dir(test_dir);
     }
 
@@ -216,7 +445,7 @@ RUN echo "BUILD_TYPE=$BUILD_TYPE" >> version.txt"#;
         let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "arg_env");
         println!("Dockerfile path: {:?}", dockerfile_path);
 
-        let mut child = Command::new("cargo")
+        let mut child = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -251,18 +480,19 @@ RUN echo "BUILD_TYPE=$BUILD_TYPE" >> version.txt"#;
 
         // Verify each line contains the expected value
         assert!(
-            lines.iter().any(|line| *line == "VERSION=1.0.0"),
+            lines.contains(&"VERSION=1.0.0"),
             "version.txt should contain VERSION=1.0.0"
         );
         assert!(
-            lines.iter().any(|line| *line == "APP_VERSION=1.0.0"),
+            lines.contains(&"APP_VERSION=1.0.0"),
             "version.txt should contain APP_VERSION=1.0.0"
         );
         assert!(
-            lines.iter().any(|line| *line == "BUILD_TYPE=release"),
+            lines.contains(&"BUILD_TYPE=release"),
             "version.txt should contain BUILD_TYPE=release"
         );
 
+        fs::remove_file(version_file).ok();
         cleanup_test_dir(test_dir);
     }
 
@@ -275,7 +505,7 @@ RUN echo "MY_VAR=$MY_VAR" > env_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "env_equals_syntax");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -305,7 +535,7 @@ RUN echo "MY_VAR=$MY_VAR" > env_space_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "env_space_syntax");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -336,7 +566,7 @@ RUN echo "FULL_VERSION=$FULL_VERSION" > expand_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "env_var_expansion");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -367,7 +597,7 @@ RUN echo "VERSION=$VERSION" > overwrite_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "env_overwrite");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -398,7 +628,7 @@ RUN echo "VERSION=$VERSION" > overwrite_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "multiline_run");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -428,7 +658,7 @@ RUN echo "MY_ARG=$MY_ARG" > arg_env_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "arg_from_env");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .env("MY_ARG", "from_environment")
             .output()
@@ -454,7 +684,6 @@ RUN echo "MY_ARG=$MY_ARG" > arg_env_test.txt"#;
     fn test_unsupported_instructions_ignored() {
         // Test that unsupported instructions are ignored without error
         let dockerfile_content = r#"FROM ubuntu:22.04
-COPY . /app
 EXPOSE 8080
 CMD ["echo", "done"]
 LABEL maintainer="test"
@@ -465,7 +694,7 @@ RUN echo "success" > unsupported_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "unsupported_instructions");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -499,7 +728,7 @@ RUN echo "VERSION=$APP_VERSION" > comment_test.txt"#;
         let (test_dir, dockerfile_path) =
             create_test_dockerfile(dockerfile_content, "comments_ignored");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -529,7 +758,7 @@ RUN export COMBINED="${PREFIX}_${SUFFIX}" && echo "COMBINED=$COMBINED" > nested_
 
         let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "nested_vars");
 
-        let output = Command::new("cargo")
+        let output = cargo_command()
             .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
             .output()
             .expect("Failed to execute command");
@@ -549,4 +778,2128 @@ RUN export COMBINED="${PREFIX}_${SUFFIX}" && echo "COMBINED=$COMBINED" > nested_
         fs::remove_file(test_file).ok();
         cleanup_test_dir(test_dir);
     }
+
+    #[test]
+    fn test_run_failure_stops_with_exit_code() {
+        // Test that a failing RUN stops the run and propagates its exit code
+        let dockerfile_content = r#"RUN echo "before" > fail_fast_test.txt
+RUN exit 3
+RUN echo "after" >> fail_fast_test.txt"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "fail_fast");
+
+        let output = cargo_command()
+            .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
+            .output()
+            .expect("Failed to execute command");
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(3));
+        assert!(
+            stderr.contains("Dockerfile line 2"),
+            "stderr should point at the failing line, got: {}",
+            stderr
+        );
+
+        let test_file = PathBuf::from("fail_fast_test.txt");
+        let content = fs::read_to_string(&test_file).expect("Failed to read fail_fast_test.txt");
+        assert_eq!(
+            content.trim(),
+            "before",
+            "steps after the failure should not run"
+        );
+
+        fs::remove_file(test_file).ok();
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_keep_going_runs_all_steps() {
+        // Test that --keep-going runs every step and reports a summary of failures
+        let dockerfile_content = r#"RUN exit 3
+RUN echo "after" > keep_going_test.txt
+RUN false"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "keep_going");
+
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+                "--keep-going",
+            ])
+            .output()
+            .expect("Failed to execute command");
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(
+            stderr.contains("2 step(s) failed"),
+            "stderr should summarize failed steps, got: {}",
+            stderr
+        );
+        assert!(stderr.contains("line 1: RUN exit 3 (exit code 3)"));
+        assert!(stderr.contains("line 3: RUN false (exit code 1)"));
+
+        let test_file = PathBuf::from("keep_going_test.txt");
+        assert!(test_file.exists(), "keep_going_test.txt should exist");
+
+        fs::remove_file(test_file).ok();
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_parser_line_continuation() {
+        // Test that continuations are joined for any instruction and line ranges are tracked
+        let dockerfile = parser::parse(
+            r#"# comment
+RUN echo "one" && \
+    echo "two"
+
+ENV GREETING \
+    hello"#,
+        )
+        .expect("Failed to parse Dockerfile");
+
+        assert_eq!(dockerfile.instructions.len(), 2);
+
+        let run = &dockerfile.instructions[0];
+        assert_eq!((run.start_line, run.end_line), (2, 3));
+        assert_eq!(
+            run.kind,
+            InstructionKind::Run(CommandForm::Shell(
+                r#"echo "one" &&     echo "two""#.to_string()
+            ))
+        );
+
+        let env = &dockerfile.instructions[1];
+        assert_eq!((env.start_line, env.end_line), (5, 6));
+        assert_eq!(
+            env.kind,
+            InstructionKind::Env(vec![("GREETING".to_string(), "hello".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_parser_flags_and_exec_form() {
+        // Test that leading flags are split off and JSON arguments become exec form
+        let dockerfile = parser::parse(
+            r#"FROM ubuntu:22.04 AS base
+RUN --mount=type=cache,target=/root/.cache ["python3", "setup.py", "install"]
+ADD --chmod=755 https://example.com/a.sh b.sh /opt/
+CMD echo done"#,
+        )
+        .expect("Failed to parse Dockerfile");
+
+        let instructions = &dockerfile.instructions;
+        assert_eq!(
+            instructions[0].kind,
+            InstructionKind::From {
+                image: "ubuntu:22.04".to_string(),
+                name: Some("base".to_string()),
+            }
+        );
+        assert_eq!(
+            instructions[1].flag("mount"),
+            Some("type=cache,target=/root/.cache")
+        );
+        assert_eq!(
+            instructions[1].kind,
+            InstructionKind::Run(CommandForm::Exec(vec![
+                "python3".to_string(),
+                "setup.py".to_string(),
+                "install".to_string(),
+            ]))
+        );
+        assert_eq!(instructions[2].flag("chmod"), Some("755"));
+        assert_eq!(
+            instructions[2].kind,
+            InstructionKind::Add {
+                sources: vec!["https://example.com/a.sh".to_string(), "b.sh".to_string()],
+                dest: "/opt/".to_string(),
+            }
+        );
+        assert_eq!(
+            instructions[3].kind,
+            InstructionKind::Cmd(CommandForm::Shell("echo done".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parser_reports_error_line() {
+        // Test that malformed instructions produce an error pointing at their line
+        let error = parser::parse("RUN echo ok\n\nADD only-one-argument")
+            .expect_err("ADD with one argument should not parse");
+        assert_eq!(error.line, 3);
+        assert_eq!(
+            error.to_string(),
+            "line 3: ADD requires at least two arguments"
+        );
+    }
+
+    #[test]
+    fn test_executor_receives_context() {
+        // Test that the executor sees WORKDIR and ENV/ARG values without touching the process
+        let dockerfile_content = r#"ARG NAME=world
+ENV GREETING=hello
+WORKDIR sub
+RUN echo "$GREETING $NAME"
+ADD https://example.com/file.txt ."#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "executor");
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(PathBuf::from("/base")),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+
+        let report = crate::run_with_executor(&options, &mut executor).expect("Run failed");
+
+        assert!(report.success());
+        assert_eq!(report.instructions, 5);
+        assert_eq!(
+            executor.actions,
+            vec![
+                "mkdir /base/sub",
+                r#"run echo "$GREETING $NAME" in /base/sub with GREETING=hello,NAME=world"#,
+                "mkdir /base/sub",
+                "download https://example.com/file.txt into /base/sub",
+            ]
+        );
+        assert!(
+            std::env::var("GREETING").is_err(),
+            "ENV should not leak into the process"
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_run_in_process() {
+        // Test running through the library with the default executor
+        let dockerfile_content = r#"ENV MESSAGE=in_process
+RUN echo "$MESSAGE" > in_process_test.txt
+RUN exit 4
+RUN echo "after" >> in_process_test.txt"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "in_process");
+        let mut options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            ..Options::default()
+        };
+
+        let error = crate::run(&options).expect_err("RUN exit 4 should stop the run");
+        assert_eq!(error.exit_code(), 4);
+        match error {
+            Error::StepFailed(step) => {
+                assert_eq!(step.start_line, 3);
+                assert_eq!(step.instruction, "RUN exit 4");
+            }
+            other => panic!("Expected a failed step, got: {}", other),
+        }
+        let content = fs::read_to_string(test_dir.join("in_process_test.txt"))
+            .expect("Failed to read in_process_test.txt");
+        assert_eq!(content, "in_process\n");
+
+        options.keep_going = true;
+        let report = crate::run(&options).expect("Run with keep_going should return a report");
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].code, 4);
+        let content = fs::read_to_string(test_dir.join("in_process_test.txt"))
+            .expect("Failed to read in_process_test.txt");
+        assert_eq!(content, "in_process\nafter\n");
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_exec_form_run() {
+        // Test that exec form runs without a shell and without variable expansion
+        let dockerfile_content = r#"ENV NAME=expanded
+RUN ["touch", "exec form $NAME.txt"]
+RUN ["sh", "-c", "echo \"$NAME\" > exec_env_test.txt"]"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "exec_form");
+
+        let output = cargo_command()
+            .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
+            .output()
+            .expect("Failed to execute command");
+
+        assert!(output.status.success());
+
+        let literal_file = PathBuf::from("exec form $NAME.txt");
+        assert!(
+            literal_file.exists(),
+            "exec form arguments should be passed through literally"
+        );
+
+        // The variable still reaches the process environment
+        let env_file = PathBuf::from("exec_env_test.txt");
+        let content = fs::read_to_string(&env_file).expect("Failed to read exec_env_test.txt");
+        assert_eq!(content.trim(), "expanded");
+
+        fs::remove_file(literal_file).ok();
+        fs::remove_file(env_file).ok();
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_exec_form_missing_program_fails() {
+        // Test that an exec form program that cannot be started fails the step
+        let (test_dir, dockerfile_path) = create_test_dockerfile(
+            r#"RUN ["dfrun-test-no-such-program", "arg"]"#,
+            "exec_form_missing",
+        );
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            ..Options::default()
+        };
+
+        match crate::run(&options) {
+            Err(Error::StepFailed(step)) => assert_eq!(step.start_line, 1),
+            other => panic!("Expected a failed step, got: {:?}", other),
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_parser_heredocs() {
+        // Test heredoc bodies, quoted delimiters, tab stripping and multiple heredocs
+        let dockerfile = parser::parse(
+        "RUN <<EOF\necho $HOME\nEOF\nRUN <<-'EOT' bash && cat <<END\n\techo tabbed\n\tEOT\n\tkept\nEND\nCOPY <<a.txt <<b.txt /dest/\nA\na.txt\nB\nb.txt\nRUN echo \"<<NOT\" <<< here",
+    )
+    .expect("Failed to parse Dockerfile");
+
+        let instructions = &dockerfile.instructions;
+        assert_eq!(instructions.len(), 4);
+
+        assert_eq!(
+            (instructions[0].start_line, instructions[0].end_line),
+            (1, 3)
+        );
+        assert_eq!(instructions[0].heredocs.len(), 1);
+        assert_eq!(instructions[0].heredocs[0].content, "echo $HOME\n");
+        assert!(instructions[0].heredocs[0].expand);
+
+        let heredocs = &instructions[1].heredocs;
+        assert_eq!(
+            (instructions[1].start_line, instructions[1].end_line),
+            (4, 8)
+        );
+        assert_eq!(heredocs.len(), 2);
+        assert_eq!(heredocs[0].name, "EOT");
+        assert_eq!(heredocs[0].content, "echo tabbed\n");
+        assert!(!heredocs[0].expand);
+        assert_eq!(heredocs[1].name, "END");
+        assert_eq!(heredocs[1].content, "\tkept\n");
+
+        let copy = &instructions[2];
+        assert_eq!(copy.heredoc_source("<<a.txt").unwrap().content, "A\n");
+        assert_eq!(copy.heredoc_source("<<b.txt").unwrap().content, "B\n");
+        assert!(copy.heredoc_source("/dest/").is_none());
+
+        assert!(instructions[3].heredocs.is_empty());
+
+        let error =
+            parser::parse("RUN <<EOF\necho never closed").expect_err("heredoc is unterminated");
+        assert_eq!(error.to_string(), "line 1: unterminated heredoc EOF");
+    }
+
+    #[test]
+    fn test_parser_heredoc_needs_word_start() {
+        // Test that `<<` inside a word, such as an arithmetic shift, is not a heredoc
+        let dockerfile = parser::parse(
+        "RUN echo $((1<<4)) > shift.txt\nRUN echo a<<b\nRUN echo '<<C' \"x <<D\"\nRUN cat <<E\ntext\nE",
+    )
+    .expect("Failed to parse Dockerfile");
+
+        let instructions = &dockerfile.instructions;
+        assert_eq!(instructions.len(), 4);
+        assert!(instructions[0].heredocs.is_empty());
+        assert!(instructions[1].heredocs.is_empty());
+        assert!(instructions[2].heredocs.is_empty());
+        assert_eq!(instructions[3].heredocs.len(), 1);
+        assert_eq!(instructions[3].heredocs[0].name, "E");
+    }
+
+    #[test]
+    fn test_run_heredocs() {
+        // Test heredoc scripts, heredocs fed to a command and shebang interpreters
+        let dockerfile_content = "ENV NAME=heredoc
+RUN <<EOF
+echo \"$NAME\" > heredoc_run_test.txt
+EOF
+RUN <<-EOT bash
+\techo \"tabbed\" >> heredoc_run_test.txt
+\tEOT
+RUN <<EOF
+#!/usr/bin/env python3
+open(\"heredoc_run_test.txt\", \"a\").write(\"python\\n\")
+EOF
+RUN echo \"done\" >> heredoc_run_test.txt";
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "heredoc_run");
+
+        let output = cargo_command()
+            .args(["run", "--", "-f", dockerfile_path.to_str().unwrap()])
+            .output()
+            .expect("Failed to execute command");
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "stderr: {}", stderr);
+
+        let test_file = PathBuf::from("heredoc_run_test.txt");
+        let content = fs::read_to_string(&test_file).expect("Failed to read heredoc_run_test.txt");
+        assert_eq!(content, "heredoc\ntabbed\npython\ndone\n");
+
+        fs::remove_file(test_file).ok();
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_copy_heredocs() {
+        // Test that COPY writes heredocs, expanding variables unless the delimiter is quoted
+        let dockerfile_content = r#"ENV VALUE=expanded
+COPY <<EOF config/app.conf
+value=$VALUE
+EOF
+COPY <<"EOF" config/raw.conf
+value=$VALUE
+EOF
+COPY <<a.txt <<b.txt multi/
+A
+a.txt
+B
+b.txt"#;
+
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "copy_heredoc");
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            ..Options::default()
+        };
+
+        crate::run(&options).expect("Run failed");
+
+        let read = |path: &str| fs::read_to_string(test_dir.join(path)).unwrap_or_default();
+        assert_eq!(read("config/app.conf"), "value=expanded\n");
+        assert_eq!(read("config/raw.conf"), "value=$VALUE\n");
+        assert_eq!(read("multi/a.txt"), "A\n");
+        assert_eq!(read("multi/b.txt"), "B\n");
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_copy_from_context() {
+        // Test COPY of files, directories and wildcards, honoring .dockerignore and --chmod
+        let dockerfile_content = r#"WORKDIR out
+COPY app app/
+COPY *.txt docs/
+COPY --chmod=640 notes.txt notes-copy.txt
+COPY app/config.yml app/main.py flat/
+COPY ../../app/main.py escaped/"#;
+
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "copy_context");
+        fs::create_dir_all(test_dir.join("app/cache")).unwrap();
+        fs::write(test_dir.join("app/config.yml"), "config").unwrap();
+        fs::write(test_dir.join("app/main.py"), "main").unwrap();
+        fs::write(test_dir.join("app/secret.env"), "secret").unwrap();
+        fs::write(test_dir.join("app/cache/module.pyc"), "bytecode").unwrap();
+        fs::write(test_dir.join("notes.txt"), "notes").unwrap();
+        fs::write(test_dir.join("todo.txt"), "todo").unwrap();
+        fs::write(
+            test_dir.join(".dockerignore"),
+            "# ignore build output\n**/*.pyc\napp/secret.env\n*.txt\n!notes.txt\n",
+        )
+        .unwrap();
+
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            ..Options::default()
+        };
+        crate::run(&options).expect("Run failed");
+
+        let out = test_dir.join("out");
+        assert_eq!(
+            fs::read_to_string(out.join("app/config.yml")).unwrap(),
+            "config"
+        );
+        assert_eq!(fs::read_to_string(out.join("app/main.py")).unwrap(), "main");
+        assert!(
+            out.join("app/cache").is_dir(),
+            "directories should be copied"
+        );
+        assert!(!out.join("app/cache/module.pyc").exists());
+        assert!(!out.join("app/secret.env").exists());
+        assert!(out.join("docs/notes.txt").exists());
+        assert!(
+            !out.join("docs/todo.txt").exists(),
+            "ignored files should not match wildcards"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("flat/main.py")).unwrap(),
+            "main"
+        );
+        assert!(
+            out.join("escaped/main.py").exists(),
+            "sources should stay inside the context"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(out.join("notes-copy.txt"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_copy_errors() {
+        // Test that missing sources and ambiguous destinations fail the step
+        let (test_dir, dockerfile_path) = create_test_dockerfile("", "copy_errors");
+        fs::write(test_dir.join("a.txt"), "a").unwrap();
+        fs::write(test_dir.join("b.txt"), "b").unwrap();
+
+        for (dockerfile_content, message) in [
+            (
+                "COPY missing.txt out.txt",
+                "missing.txt not found in build context",
+            ),
+            (
+                "COPY a.txt b.txt out",
+                "the destination must be a directory",
+            ),
+            ("COPY *.txt out", "the destination must be a directory"),
+            (
+                "COPY --chmod=abc a.txt out.txt",
+                "invalid --chmod value: abc",
+            ),
+        ] {
+            fs::write(&dockerfile_path, dockerfile_content).unwrap();
+            let options = Options {
+                dockerfile: dockerfile_path.clone(),
+                workdir: Some(test_dir.clone()),
+                ..Options::default()
+            };
+            match crate::run(&options) {
+                Err(Error::StepFailed(step)) => assert!(
+                    step.reason.contains(message),
+                    "{}: expected {:?} in {:?}",
+                    dockerfile_content,
+                    message,
+                    step.reason
+                ),
+                other => panic!(
+                    "{}: expected a failed step, got {:?}",
+                    dockerfile_content, other
+                ),
+            }
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    /// Writes a tar archive holding `files` to `path`, compressed with `compress`
+    fn write_tar<W: Write>(compress: W, files: &[(&str, &str)]) -> W {
+        let mut builder = tar::Builder::new(compress);
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_add_local_files_and_archives() {
+        // Test that ADD copies local files, unpacks tar archives and downloads into its destination
+        let url = start_http_server(|_| http_response("200 OK", &[], b"remote data"));
+        let dockerfile_content = format!(
+            r#"WORKDIR out
+ADD config.txt conf/
+ADD site.tar.gz site/
+ADD assets.tar.xz assets
+ADD plain.tar plain/
+ADD notes.gz notes/
+ADD {url}/data.bin?version=1 downloads/
+ADD --chmod=600 {url}/data.bin renamed.bin
+ADD {url}/ ."#
+        );
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(&dockerfile_content, "add_local");
+        fs::write(test_dir.join("config.txt"), "config").unwrap();
+        let gz = write_tar(
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()),
+            &[("index.html", "<h1>hi</h1>"), ("css/site.css", "body {}")],
+        );
+        fs::write(test_dir.join("site.tar.gz"), gz.finish().unwrap()).unwrap();
+        let xz = write_tar(
+            xz2::write::XzEncoder::new(Vec::new(), 6),
+            &[("logo.svg", "<svg/>")],
+        );
+        fs::write(test_dir.join("assets.tar.xz"), xz.finish().unwrap()).unwrap();
+        fs::write(
+            test_dir.join("plain.tar"),
+            write_tar(Vec::new(), &[("readme.md", "plain")]),
+        )
+        .unwrap();
+        // Compressed files that are not tar archives are copied as they are
+        let mut notes = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        notes.write_all(b"not a tar").unwrap();
+        fs::write(test_dir.join("notes.gz"), notes.finish().unwrap()).unwrap();
+
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            no_cache: true,
+            ..Options::default()
+        };
+        crate::run(&options).expect("Run failed");
+
+        let out = test_dir.join("out");
+        assert_eq!(
+            fs::read_to_string(out.join("conf/config.txt")).unwrap(),
+            "config"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("site/index.html")).unwrap(),
+            "<h1>hi</h1>"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("site/css/site.css")).unwrap(),
+            "body {}"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("assets/logo.svg")).unwrap(),
+            "<svg/>"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("plain/readme.md")).unwrap(),
+            "plain"
+        );
+        assert!(out.join("notes/notes.gz").is_file());
+        assert_eq!(
+            fs::read_to_string(out.join("downloads/data.bin")).unwrap(),
+            "remote data"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("renamed.bin")).unwrap(),
+            "remote data"
+        );
+        assert!(out.join("download").is_file(), "URLs without a file name");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(out.join("renamed.bin"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_download_redirects_and_file_names() {
+        // Test that downloads follow redirects and take their name from Content-Disposition
+        let url = start_http_server(|request| {
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            match path {
+            "/latest" => http_response("302 Found", &[("Location", "/v2/tool.sh")], b""),
+            "/v2/tool.sh" => http_response("200 OK", &[], b"echo tool"),
+            "/export?id=7" => http_response(
+                "200 OK",
+                &[(
+                    "Content-Disposition",
+                    "attachment; filename=\"../report.csv\"; filename*=UTF-8''sales%20report.csv",
+                )],
+                b"a,b",
+            ),
+            _ => http_response("404 Not Found", &[], b""),
+        }
+        });
+        let dockerfile_content = format!(
+            r#"ADD {url}/latest bin/
+ADD {url}/latest tool
+ADD {url}/export?id=7 reports/"#
+        );
+
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(&dockerfile_content, "download_names");
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            no_cache: true,
+            ..Options::default()
+        };
+        crate::run(&options).expect("Run failed");
+
+        // As in Docker, URL names come from the URL in the Dockerfile, not the redirect target
+        assert_eq!(
+            fs::read_to_string(test_dir.join("bin/latest")).unwrap(),
+            "echo tool"
+        );
+        assert_eq!(
+            fs::read_to_string(test_dir.join("tool")).unwrap(),
+            "echo tool"
+        );
+        assert_eq!(
+            fs::read_to_string(test_dir.join("reports/sales report.csv")).unwrap(),
+            "a,b"
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_download_retries_and_failures() {
+        // Test that server errors are retried and failed downloads leave no partial files behind
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let url = start_http_server(move |request| {
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            match path {
+                "/flaky.txt" if counter.fetch_add(1, Ordering::SeqCst) == 0 => {
+                    http_response("503 Service Unavailable", &[], b"")
+                }
+                "/flaky.txt" => http_response("200 OK", &[], b"eventually"),
+                _ => http_response("404 Not Found", &[], b"missing"),
+            }
+        });
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(
+            &format!("ADD {url}/flaky.txt downloads/\nADD {url}/missing.txt downloads/"),
+            "download_retries",
+        );
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            no_cache: true,
+            ..Options::default()
+        };
+        match crate::run(&options) {
+            Err(Error::StepFailed(step)) => {
+                assert_eq!(step.start_line, 2);
+                assert!(step.reason.contains("404"), "reason: {}", step.reason);
+            }
+            other => panic!("expected the missing download to fail, got {:?}", other),
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            fs::read_to_string(test_dir.join("downloads/flaky.txt")).unwrap(),
+            "eventually"
+        );
+        let files: Vec<_> = fs::read_dir(test_dir.join("downloads"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["flaky.txt"]);
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_add_checksum() {
+        // Test that ADD --checksum keeps matching downloads and rejects everything else
+        let url = start_http_server(|_| http_response("200 OK", &[], b"hello world"));
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let (test_dir, dockerfile_path) = create_test_dockerfile(
+            &format!("ADD --checksum=sha256:{sha256} {url}/hello.txt downloads/"),
+            "add_checksum",
+        );
+        fs::write(test_dir.join("local.txt"), "hello world").unwrap();
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(test_dir.clone()),
+            no_cache: true,
+            ..Options::default()
+        };
+        crate::run(&options).expect("Run failed");
+        assert_eq!(
+            fs::read_to_string(test_dir.join("downloads/hello.txt")).unwrap(),
+            "hello world"
+        );
+
+        let wrong = "0".repeat(64);
+        for (dockerfile_content, message) in [
+        (
+            format!("ADD --checksum=sha256:{wrong} {url}/hello.txt rejected/"),
+            format!("checksum mismatch for {url}/hello.txt: expected sha256:{wrong}, got sha256:{sha256}"),
+        ),
+        (
+            format!("ADD --checksum=md5:abc {url}/hello.txt rejected/"),
+            "unsupported checksum algorithm md5".to_string(),
+        ),
+        (
+            format!("ADD --checksum=sha256:{sha256} local.txt rejected/"),
+            "--checksum is only supported for HTTP(S) sources".to_string(),
+        ),
+    ] {
+        fs::write(&dockerfile_path, &dockerfile_content).unwrap();
+        match crate::run(&options) {
+            Err(Error::StepFailed(step)) => assert!(
+                step.reason.contains(&message),
+                "{}: expected {:?} in {:?}",
+                dockerfile_content,
+                message,
+                step.reason
+            ),
+            other => panic!("{}: expected a failed step, got {:?}", dockerfile_content, other),
+        }
+        let rejected: Vec<_> = fs::read_dir(test_dir.join("rejected"))
+            .map(|entries| entries.map(|entry| entry.unwrap().file_name()).collect())
+            .unwrap_or_default();
+        assert!(rejected.is_empty(), "files were left behind: {:?}", rejected);
+    }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_download_cache() {
+        // Test that cached downloads are revalidated with their ETag or, when pinned, not requested
+        use std::sync::{Arc, Mutex};
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requests);
+        let url = start_http_server(move |request| {
+            let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+            let revalidating = request.contains("If-None-Match: \"v1\"");
+            log.lock().unwrap().push(format!(
+                "{}{}",
+                path,
+                if revalidating { " (revalidated)" } else { "" }
+            ));
+            match path.as_str() {
+                "/model.bin" if revalidating => http_response("304 Not Modified", &[], b""),
+                "/model.bin" => http_response("200 OK", &[("ETag", "\"v1\"")], b"weights"),
+                _ => http_response("200 OK", &[], b"pinned"),
+            }
+        });
+        let mut hasher = crate::checksum::Hasher::new(crate::Algorithm::Sha256);
+        hasher.update(b"pinned");
+        let checksum = hasher.finish();
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(
+            &format!(
+                "ADD {url}/model.bin models/\nADD --checksum={checksum} {url}/pinned.bin pinned/"
+            ),
+            "download_cache",
+        );
+        let cache_dir = test_dir.join("cache/dfrun");
+        let mut options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            cache_dir: Some(cache_dir.clone()),
+            ..Options::default()
+        };
+        let run_and_check = |options: &Options| {
+            fs::remove_dir_all(test_dir.join("models")).ok();
+            fs::remove_dir_all(test_dir.join("pinned")).ok();
+            crate::run(options).expect("Run failed");
+            assert_eq!(
+                fs::read_to_string(test_dir.join("models/model.bin")).unwrap(),
+                "weights"
+            );
+            assert_eq!(
+                fs::read_to_string(test_dir.join("pinned/pinned.bin")).unwrap(),
+                "pinned"
+            );
+        };
+
+        run_and_check(&options);
+        run_and_check(&options);
+        options.no_cache = true;
+        run_and_check(&options);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "/model.bin",
+                "/pinned.bin",
+                "/model.bin (revalidated)",
+                "/model.bin",
+                "/pinned.bin",
+            ]
+        );
+
+        // `dfrun cache prune` keeps the most recently used downloads that fit
+        let output = cargo_command()
+            .args(["run", "--", "cache", "prune", "--max-size", "6"])
+            .env(
+                "XDG_CACHE_HOME",
+                test_dir.join("cache").canonicalize().unwrap(),
+            )
+            .output()
+            .expect("Failed to execute command");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success());
+        assert!(stdout.contains("Removed 1 file(s)"), "stdout: {}", stdout);
+        let pinned_blob = cache_dir
+            .join("blobs")
+            .join(checksum.to_string().replace(':', "/"));
+        assert!(pinned_blob.is_file());
+
+        let pruned = crate::Cache::new(&cache_dir).prune(0).unwrap();
+        assert_eq!((pruned.files, pruned.bytes), (1, 6));
+        assert_eq!(fs::read_dir(cache_dir.join("urls")).unwrap().count(), 0);
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_git_source_parsing() {
+        // Test which ADD sources are git repositories, and their ref and subdirectory
+        let git = |url: &str, reference: Option<&str>, subdir: Option<&str>| GitSource {
+            url: url.to_string(),
+            reference: reference.map(str::to_string),
+            subdir: subdir.map(str::to_string),
+        };
+        let cases = [
+            (
+                "https://github.com/org/repo.git#v1.2",
+                Some(git("https://github.com/org/repo.git", Some("v1.2"), None)),
+            ),
+            (
+                "git@github.com:org/repo.git#main:docs/site",
+                Some(git(
+                    "git@github.com:org/repo.git",
+                    Some("main"),
+                    Some("docs/site"),
+                )),
+            ),
+            (
+                "https://example.com/repo.git?ref=v2&subdir=lib",
+                Some(git("https://example.com/repo.git", Some("v2"), Some("lib"))),
+            ),
+            (
+                "ssh://git@example.com/repo#:tools",
+                Some(git("ssh://git@example.com/repo", None, Some("tools"))),
+            ),
+            ("https://example.com/archive.tar.gz", None),
+            ("src/repo.git", None),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(GitSource::parse(source), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_add_git_repository() {
+        // Test cloning a local bare repository at a tag, a commit and a subdirectory
+        let (test_dir, dockerfile_path) = create_test_dockerfile("", "add_git");
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .args([
+                    "-c",
+                    "user.name=dfrun",
+                    "-c",
+                    "user.email=dfrun@example.com",
+                ])
+                .args(args)
+                .current_dir(&test_dir)
+                .output()
+                .expect("Failed to run git");
+            assert!(output.status.success(), "git {:?}: {:?}", args, output);
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+        git(&["init", "--quiet", "work"]);
+        fs::create_dir_all(test_dir.join("work/docs")).unwrap();
+        fs::write(test_dir.join("work/README.md"), "v1").unwrap();
+        fs::write(test_dir.join("work/docs/guide.md"), "guide v1").unwrap();
+        git(&["-C", "work", "add", "."]);
+        git(&["-C", "work", "commit", "--quiet", "-m", "v1"]);
+        git(&["-C", "work", "tag", "v1"]);
+        let v1_commit = git(&["-C", "work", "rev-parse", "HEAD"]);
+        fs::write(test_dir.join("work/README.md"), "v2").unwrap();
+        git(&["-C", "work", "commit", "--quiet", "-am", "v2"]);
+        git(&["clone", "--quiet", "--bare", "work", "repo.git"]);
+
+        let repo = format!(
+            "file://{}",
+            test_dir.join("repo.git").canonicalize().unwrap().display()
+        );
+        fs::write(
+            &dockerfile_path,
+            format!(
+                r#"ADD {repo} latest
+ADD {repo}#v1 tagged/
+ADD {repo}#{v1_commit}:docs pinned-docs
+ADD --keep-git-dir=true {repo}?ref=v1 with-git/"#
+            ),
+        )
+        .unwrap();
+        let options = Options {
+            dockerfile: dockerfile_path,
+            workdir: Some(test_dir.clone()),
+            ..Options::default()
+        };
+        crate::run(&options).expect("Run failed");
+
+        let read = |path: &str| fs::read_to_string(test_dir.join(path)).unwrap();
+        assert_eq!(read("latest/README.md"), "v2");
+        assert!(!test_dir.join("latest/.git").exists());
+        assert_eq!(read("tagged/README.md"), "v1");
+        assert_eq!(read("pinned-docs/guide.md"), "guide v1");
+        assert!(!test_dir.join("pinned-docs/README.md").exists());
+        assert_eq!(read("with-git/README.md"), "v1");
+        assert!(test_dir.join("with-git/.git").is_dir());
+
+        fs::write(
+            test_dir.join("Dockerfile"),
+            format!("ADD {repo}#no-such-tag missing/"),
+        )
+        .unwrap();
+        match crate::run(&options) {
+            Err(Error::StepFailed(step)) => {
+                assert!(step.reason.contains("git clone failed"), "{}", step.reason)
+            }
+            other => panic!("expected the clone to fail, got {:?}", other),
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_parser_stages() {
+        // Test splitting a Dockerfile into stages and finding what each depends on
+        let dockerfile = parser::parse(
+            r#"ARG VERSION=1
+FROM rust:1 AS Builder
+RUN cargo build
+FROM builder AS test
+RUN cargo test
+FROM alpine
+COPY --from=Builder /target/app /app
+COPY --from=1 /report /report
+COPY --from=nginx:latest /etc/nginx /etc/nginx"#,
+        )
+        .expect("Failed to parse");
+
+        assert_eq!(dockerfile.preamble().len(), 1);
+        let stages = dockerfile.stages();
+        let summary: Vec<_> = stages
+            .iter()
+            .map(|stage| {
+                (
+                    stage.name.as_deref(),
+                    stage.base,
+                    stage.instructions.len(),
+                    stage.dependencies(&stages),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some("builder"), None, 1, vec![]),
+                (Some("test"), Some(0), 1, vec![0]),
+                (None, None, 3, vec![0, 1]),
+            ]
+        );
+        assert_eq!(parser::find_stage(&stages, "TEST"), Some(1));
+        assert_eq!(parser::find_stage(&stages, "2"), Some(2));
+        assert_eq!(parser::find_stage(&stages, "3"), None);
+    }
+
+    #[test]
+    fn test_multi_stage_target() {
+        // Test that only the target's stages run, each in its own scope
+        let dockerfile_content = r#"ARG GLOBAL=1
+FROM ubuntu AS deps
+ENV DEPS=1
+WORKDIR /deps
+RUN echo deps
+FROM deps AS build
+RUN echo build
+FROM ubuntu AS test
+RUN echo test
+FROM ubuntu
+ARG GLOBAL
+RUN echo final"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "stages");
+        let run = |target: Option<&str>| {
+            let options = Options {
+                dockerfile: dockerfile_path.clone(),
+                workdir: Some(PathBuf::from("/base")),
+                target: target.map(str::to_string),
+                ..Options::default()
+            };
+            let mut executor = RecordingExecutor::default();
+            crate::run_with_executor(&options, &mut executor)
+                .map(|report| (report, executor.actions))
+        };
+
+        let (report, actions) = run(None).expect("Run failed");
+        assert_eq!(report.instructions, 4);
+        assert_eq!(actions, vec!["run echo final in /base with GLOBAL=1"]);
+
+        let (_, actions) = run(Some("build")).expect("Run failed");
+        assert_eq!(
+            actions,
+            vec![
+                "mkdir /deps",
+                "run echo deps in /deps with DEPS=1",
+                "run echo build in /deps with DEPS=1",
+            ]
+        );
+
+        match run(Some("release")) {
+            Err(Error::UnknownTarget(target)) => assert_eq!(target, "release"),
+            other => panic!(
+                "expected an unknown target error, got {:?}",
+                other.map(|(r, _)| r)
+            ),
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_copy_from_stage() {
+        // Test that COPY --from reads from the root of an earlier stage
+        let dockerfile_content = r#"FROM alpine AS builder
+WORKDIR /out
+RUN echo built > app
+FROM builder AS checked
+RUN echo checked > ../check
+FROM alpine
+COPY --from=builder /out/app /bin/
+COPY --from=1 check /
+RUN cat bin/app check"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "copy_from");
+        let stage_dir = test_dir.join("stages");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(test_dir.clone()),
+            stage_dir: Some(stage_dir.clone()),
+            ..Options::default()
+        };
+        crate::run(&options).expect("Run failed");
+
+        assert_eq!(
+            fs::read_to_string(stage_dir.join("builder/out/app")).unwrap(),
+            "built\n"
+        );
+        // A stage built on another shares its root
+        assert!(stage_dir.join("builder/check").is_file());
+        assert!(!stage_dir.join("checked").exists());
+        assert_eq!(
+            fs::read_to_string(stage_dir.join("2/bin/app")).unwrap(),
+            "built\n"
+        );
+        assert!(stage_dir.join("2/check").is_file());
+
+        fs::write(
+            &dockerfile_path,
+            "FROM alpine\nCOPY --from=nginx:latest /etc/nginx /etc/nginx",
+        )
+        .unwrap();
+        let error = crate::run(&options).expect_err("Copying from an image should fail");
+        assert!(
+            error.to_string().contains("nginx:latest"),
+            "unexpected error: {}",
+            error
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_arg_scoping() {
+        // Test that ARGs follow Docker's scoping: ARGs before FROM only reach a
+        // stage that declares them again, and no ARG outlives its stage
+        let dockerfile_content = r#"ARG VERSION=1.0
+ARG BASE=alpine:$VERSION
+FROM $BASE AS build
+ARG VERSION
+ARG MODE=debug
+ENV MODE=release
+RUN echo build
+FROM build
+ARG VERSION=2.0
+RUN echo test $VERSION
+WORKDIR $BUILD_ROOT/out"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "arg_scoping");
+        let mut options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        crate::run_with_executor(&options, &mut executor).expect("Run failed");
+        assert_eq!(
+            executor.actions,
+            vec![
+                "run echo build in /base with MODE=release,VERSION=1.0",
+                "run echo test $VERSION in /base with MODE=release,VERSION=2.0",
+                "mkdir /out",
+            ]
+        );
+
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "--docker-compat",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+            ])
+            .env("BUILD_ROOT", fs::canonicalize(&test_dir).unwrap())
+            .env_remove("VERSION")
+            .output()
+            .expect("Failed to execute command");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "stderr: {}", stderr);
+        assert!(
+            stderr.contains("line 11: BUILD_ROOT is not declared with ARG or ENV"),
+            "stderr: {}",
+            stderr
+        );
+        assert!(!stderr.contains("is not defined"), "stderr: {}", stderr);
+
+        // FROM expands ARGs from before the first FROM, but not a stage's
+        options.strict = true;
+        fs::write(
+            &dockerfile_path,
+            "ARG BASE=alpine\nFROM ${BASE}\nRUN echo build",
+        )
+        .unwrap();
+        crate::run_with_executor(&options, &mut RecordingExecutor::default()).expect("Run failed");
+        fs::write(
+            &dockerfile_path,
+            "ARG BASE=alpine\nFROM $BASE\nARG NEXT=alpine\nFROM $NEXT",
+        )
+        .unwrap();
+        match crate::run_with_executor(&options, &mut RecordingExecutor::default()) {
+            Err(Error::Substitution(e)) => {
+                assert_eq!(e.message, "NEXT is not defined");
+                assert_eq!((e.line, e.column), (4, 6));
+            }
+            other => panic!("expected an undefined variable error, got {:?}", other),
+        }
+
+        fs::write(
+            &dockerfile_path,
+            "ARG VERSION=1.0\nFROM alpine\nRUN echo $VERSION",
+        )
+        .unwrap();
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "--docker-compat",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+            ])
+            .output()
+            .expect("Failed to execute command");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("line 3: VERSION is declared before the first FROM"),
+            "stderr: {}",
+            stderr
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_build_args() {
+        // Test that build args win over the environment and defaults, with
+        // --build-arg taking precedence over the file
+        let dockerfile_content = r#"ARG FROM_FILE
+ARG OVERRIDDEN=default
+ARG DEFAULTED=default
+RUN echo build"#;
+
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "build_args");
+        let arg_file = test_dir.join("args.env");
+        fs::write(
+            &arg_file,
+            "# build settings\nFROM_FILE=file value\n\nOVERRIDDEN=from file\nUNUSED=1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            crate::parse_build_arg_file(&fs::read_to_string(&arg_file).unwrap())
+                .get("FROM_FILE")
+                .and_then(|value| value.as_deref()),
+            Some("file value")
+        );
+
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            build_args: [("OVERRIDDEN".to_string(), Some("from flag".to_string()))].into(),
+            build_arg_file: Some(arg_file.clone()),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        crate::run_with_executor(&options, &mut executor).expect("Run failed");
+        assert_eq!(
+        executor.actions,
+        vec!["run echo build in /base with DEFAULTED=default,FROM_FILE=file value,OVERRIDDEN=from flag"]
+    );
+
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+                "--build-arg-file",
+                arg_file.to_str().unwrap(),
+                "--build-arg",
+                "DEFAULTED=flag",
+            ])
+            .env("FROM_FILE", "from environment")
+            .output()
+            .expect("Failed to execute command");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "stderr: {}", stderr);
+        assert!(
+            stderr.contains("Build args not used by any ARG: UNUSED"),
+            "stderr: {}",
+            stderr
+        );
+
+        let missing = Options {
+            build_arg_file: Some(test_dir.join("missing.env")),
+            ..options
+        };
+        match crate::run_with_executor(&missing, &mut RecordingExecutor::default()) {
+            Err(Error::BuildArgFile(path, _)) => assert_eq!(path, test_dir.join("missing.env")),
+            other => panic!("expected a build arg file error, got {:?}", other),
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_bare_build_arg_unset() {
+        // Test that a bare --build-arg whose variable is unset falls back to the
+        // ARG default and still counts as given when unused
+        let dockerfile_content = r#"ARG DFRUN_BARE_ARG=default
+RUN echo build"#;
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "bare_build_arg");
+
+        std::env::remove_var("DFRUN_BARE_ARG");
+        assert_eq!(
+            crate::parse_build_arg("DFRUN_BARE_ARG"),
+            ("DFRUN_BARE_ARG".to_string(), None)
+        );
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            build_args: [("DFRUN_BARE_ARG".to_string(), None)].into(),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        crate::run_with_executor(&options, &mut executor).expect("Run failed");
+        assert_eq!(
+            executor.actions,
+            vec!["run echo build in /base with DFRUN_BARE_ARG=default"]
+        );
+
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+                "--build-arg",
+                "DFRUN_BARE_ARG",
+                "--build-arg",
+                "DFRUN_UNUSED_BARE_ARG",
+            ])
+            .env_remove("DFRUN_BARE_ARG")
+            .env_remove("DFRUN_UNUSED_BARE_ARG")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to execute command");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "stderr: {}", stderr);
+        assert!(
+            stderr.contains("Build args not used by any ARG: DFRUN_UNUSED_BARE_ARG\n"),
+            "stderr: {}",
+            stderr
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+    #[test]
+    fn test_substitution_modifiers() {
+        // Test Docker's substitution grammar against a fixed set of variables
+        let lookup = |name: &str| match name {
+            "FILE" => Some("archive.tar.gz".to_string()),
+            "PATHS" => Some("/usr/local/bin:/usr/bin".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |word: &str| crate::expand::expand(word, '\\', &lookup);
+        let value = |word: &str| expand(word).map(|expanded| expanded.value);
+        let cases = [
+            ("$FILE and ${FILE}", "archive.tar.gz and archive.tar.gz"),
+            ("${UNSET:-default}", "default"),
+            ("${EMPTY:-default}|${EMPTY-default}", "default|"),
+            ("${FILE:-default}", "archive.tar.gz"),
+            ("${UNSET:-${FILE}}", "archive.tar.gz"),
+            (
+                "${FILE:+set}|${EMPTY:+set}|${EMPTY+set}|${UNSET+set}",
+                "set||set|",
+            ),
+            ("${FILE#*.}|${FILE##*.}", "tar.gz|gz"),
+            ("${FILE%.*}|${FILE%%.*}", "archive.tar|archive"),
+            ("${FILE#nomatch}", "archive.tar.gz"),
+            ("${PATHS/usr/opt}", "/opt/local/bin:/usr/bin"),
+            ("${PATHS//usr/opt}", "/opt/local/bin:/opt/bin"),
+            ("${PATHS//:*}", "/usr/local/bin"),
+            ("${FILE/.tar}", "archive.gz"),
+            (
+                r"\$FILE costs $5 and \${FILE}",
+                "$FILE costs $5 and ${FILE}",
+            ),
+            (r"C:\dir\$FILE", r"C:\dir$FILE"),
+        ];
+        for (word, expected) in cases {
+            assert_eq!(value(word).as_deref(), Ok(expected), "expanding {}", word);
+        }
+
+        let error = expand("image:${TAG:?must be set}").unwrap_err();
+        assert_eq!(error.offset, 6);
+        assert_eq!(error.message, "TAG: must be set");
+        assert_eq!(
+            expand("${EMPTY:?}").unwrap_err().message,
+            "EMPTY: parameter null or not set"
+        );
+        assert!(expand("${FILE")
+            .unwrap_err()
+            .message
+            .contains("missing '}'"));
+        assert!(expand("${FILE:#x}")
+            .unwrap_err()
+            .message
+            .contains("unsupported modifier"));
+
+        // Substitutions apply to ARG defaults and report where they failed
+        let dockerfile_content = r#"ARG NAME=app
+ARG ARCHIVE=${NAME}-${VERSION:-1.0}.tar.gz
+WORKDIR /opt/${ARCHIVE%.tar.gz}
+WORKDIR /srv/${REQUIRED:?set REQUIRED}"#;
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "substitution");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        match crate::run_with_executor(&options, &mut executor) {
+            Err(Error::Substitution(e)) => {
+                assert_eq!((e.line, e.column), (4, 14));
+                assert_eq!(e.message, "REQUIRED: set REQUIRED");
+            }
+            other => panic!("expected a substitution error, got {:?}", other),
+        }
+        assert_eq!(executor.actions, vec!["mkdir /opt/app-1.0"]);
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_strict_undefined_variables() {
+        // Test that undefined variables are reported, and fail the run with strict
+        let undefined =
+            crate::expand::expand("$UNSET ${UNSET:-x} ${UNSET+y} ${UNSET%.gz}", '\\', &|_| {
+                None
+            })
+            .unwrap()
+            .undefined;
+        let offsets: Vec<usize> = undefined.iter().map(|u| u.offset).collect();
+        assert_eq!(offsets, vec![0, 30]);
+
+        let dockerfile_content = r#"ARG APP_VERSION=1.0
+ENV VERSION=$APP_VERSION
+ENV LABEL=v$APP_VERSON
+RUN echo done"#;
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "strict");
+        let mut options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        crate::run_with_executor(&options, &mut executor).expect("Run failed");
+        assert_eq!(
+            executor.actions,
+            vec!["run echo done in /base with APP_VERSION=1.0,LABEL=v,VERSION=1.0"]
+        );
+
+        options.strict = true;
+        let mut executor = RecordingExecutor::default();
+        match crate::run_with_executor(&options, &mut executor) {
+            Err(Error::Substitution(e)) => {
+                assert_eq!(e.message, "APP_VERSON is not defined");
+                assert_eq!((e.line, e.column), (3, 12));
+            }
+            other => panic!("expected an undefined variable error, got {:?}", other),
+        }
+        assert!(executor.actions.is_empty());
+
+        // Columns point at the argument being expanded, even when the same
+        // text appears earlier in the instruction
+        for (content, name, column) in [
+            ("ENV A='$NOPE' B=$NOPE", "NOPE", 17),
+            ("COPY $NOPE2/x $NOPE2/", "NOPE2", 15),
+            ("COPY --chmod=$MODE $MODE /", "MODE", 14),
+        ] {
+            fs::write(&dockerfile_path, content).unwrap();
+            let mut executor = RecordingExecutor::default();
+            // COPY reports the error as the reason its step failed
+            let reason = match crate::run_with_executor(&options, &mut executor) {
+                Err(Error::Substitution(e)) => e.to_string(),
+                Err(Error::StepFailed(step)) => step.reason,
+                other => panic!("expected an undefined variable error, got {:?}", other),
+            };
+            let expected = format!("line 1, column {}: {} is not defined", column, name);
+            assert!(reason.ends_with(&expected), "{}", reason);
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_env_pairs_and_quoting() {
+        // Test that ENV and LABEL take several pairs with Docker's quoting rules
+        let dockerfile = parser::parse(
+            r#"ENV A=1 B="two words" C=3
+ENV LEGACY value with spaces
+LABEL "com.example.vendor"="ACME Inc" version=1.0
+ENV BROKEN"#,
+        );
+        assert_eq!(dockerfile.unwrap_err().line, 4);
+        let dockerfile =
+            parser::parse("ENV A=1 B=\"two words\" C=3\nLABEL \"com.example.vendor\"=\"ACME\" v=1")
+                .unwrap();
+        assert_eq!(
+            dockerfile.instructions[0].kind,
+            InstructionKind::Env(vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "\"two words\"".to_string()),
+                ("C".to_string(), "3".to_string()),
+            ])
+        );
+        assert_eq!(
+            dockerfile.instructions[1].kind,
+            InstructionKind::Label(vec![
+                ("com.example.vendor".to_string(), "\"ACME\"".to_string()),
+                ("v".to_string(), "1".to_string()),
+            ])
+        );
+        assert!(parser::parse("ENV A=1 B").is_err());
+
+        let dockerfile_content = r#"ENV NAME=app
+ENV A=1 B="two words" C=3
+ENV SINGLE='$NAME stays' DOUBLE="$NAME \"quoted\"" ESCAPED=one\ two NAME=new COPY=$NAME
+ENV LEGACY value with  spaces
+ARG QUOTED="x y"
+RUN echo done"#;
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "env_pairs");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        crate::run_with_executor(&options, &mut executor).expect("Run failed");
+        assert_eq!(
+            executor.actions,
+            vec![concat!(
+                "run echo done in /base with A=1,B=two words,C=3,COPY=app,",
+                "DOUBLE=app \"quoted\",ESCAPED=one two,LEGACY=value with  spaces,",
+                "NAME=new,QUOTED=x y,SINGLE=$NAME stays"
+            )]
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_parser_directives() {
+        // Test that `# escape=` changes line continuations and escaping
+        let dockerfile = parser::parse(
+        "# escape=`\n# syntax=docker/dockerfile:1\n# check=skip=JSONArgsRecommended,StageNameCasing;error=true\nFROM windows\nWORKDIR c:\\\nENV A=1 `\n    B=one` two\nRUN dir c:\\",
+    )
+    .unwrap();
+        assert_eq!(dockerfile.directives.escape, '`');
+        assert_eq!(
+            dockerfile.directives.syntax.as_deref(),
+            Some("docker/dockerfile:1")
+        );
+        assert_eq!(
+            dockerfile.directives.check,
+            parser::Check {
+                skip: vec![
+                    "JSONArgsRecommended".to_string(),
+                    "StageNameCasing".to_string()
+                ],
+                error: true,
+            }
+        );
+        let kinds: Vec<&InstructionKind> = dockerfile
+            .instructions
+            .iter()
+            .map(|instruction| &instruction.kind)
+            .collect();
+        assert_eq!(kinds[1], &InstructionKind::Workdir("c:\\".to_string()));
+        assert_eq!(
+            kinds[2],
+            &InstructionKind::Env(vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "one` two".to_string()),
+            ])
+        );
+        assert_eq!(
+            kinds[3],
+            &InstructionKind::Run(CommandForm::Shell("dir c:\\".to_string()))
+        );
+
+        // Directives are only read before anything else, and only once each
+        let dockerfile = parser::parse("# comment\n# escape=`\nRUN echo \\\n  hi").unwrap();
+        assert_eq!(dockerfile.directives.escape, '\\');
+        assert_eq!(dockerfile.instructions.len(), 1);
+        assert_eq!(
+            parser::parse("# escape=`\n# ESCAPE=\\\nRUN x")
+                .unwrap_err()
+                .message,
+            "only one escape parser directive can be used"
+        );
+        assert_eq!(parser::parse("# escape=/\nRUN x").unwrap_err().line, 1);
+        assert!(parser::parse("# check=error=maybe\nRUN x").is_err());
+
+        assert_eq!(crate::syntax_warning("docker/dockerfile:1.7"), None);
+        assert_eq!(
+            crate::syntax_warning("docker.io/docker/dockerfile@sha256:abc"),
+            None
+        );
+        assert!(crate::syntax_warning("docker/dockerfile:1.7-labs")
+            .unwrap()
+            .contains("experimental"));
+        assert!(crate::syntax_warning("example.com/frontend:2")
+            .unwrap()
+            .contains("custom frontend"));
+
+        // The escape character also applies when values are expanded
+        let dockerfile_content = "# escape=`\nENV A=1 `\n    B=one` two C=`$A\nRUN echo done";
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "directives");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            workdir: Some(PathBuf::from("/base")),
+            ..Options::default()
+        };
+        let mut executor = RecordingExecutor::default();
+        crate::run_with_executor(&options, &mut executor).expect("Run failed");
+        assert_eq!(
+            executor.actions,
+            vec!["run echo done in /base with A=1,B=one two,C=$A"]
+        );
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_keywords_and_continuations() {
+        // Test lowercase keywords and continuations with comment and blank lines
+        let dockerfile = parser::parse(
+        "from alpine As build\nrun apt-get update \\\n    # install tools\n\n    && apt-get install -y curl\nEnv A=1 \\\n    B=2\nworkdir /app",
+    )
+    .unwrap();
+        let instructions = &dockerfile.instructions;
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(
+            instructions[0].kind,
+            InstructionKind::From {
+                image: "alpine".to_string(),
+                name: Some("build".to_string()),
+            }
+        );
+        assert_eq!(
+            instructions[1].kind,
+            InstructionKind::Run(CommandForm::Shell(
+                "apt-get update     && apt-get install -y curl".to_string()
+            ))
+        );
+        assert_eq!(
+            (instructions[1].start_line, instructions[1].end_line),
+            (2, 5)
+        );
+        assert_eq!(
+            instructions[2].kind,
+            InstructionKind::Env(vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "2".to_string()),
+            ])
+        );
+        assert_eq!(
+            instructions[3].kind,
+            InstructionKind::Workdir("/app".to_string())
+        );
+        assert_eq!(dockerfile.stages()[0].name.as_deref(), Some("build"));
+    }
+
+    #[test]
+    fn test_dry_run_plan() {
+        // Test that a plan lists each instruction's actions without taking them
+        let dockerfile_content = r#"ARG VERSION=1.0
+FROM alpine
+ARG VERSION
+ENV APP=/opt/app
+WORKDIR $APP
+RUN echo $VERSION > version.txt
+ADD --chmod=755 https://example.com/tool-${VERSION}.sh /usr/local/bin/tool"#;
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "plan");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            build_args: [("VERSION".to_string(), Some("2.0".to_string()))].into(),
+            ..Options::default()
+        };
+        let mut executor = crate::PlanExecutor::new(Vec::new());
+        crate::run_with_executor(&options, &mut executor).expect("Plan failed");
+        let plan = String::from_utf8(executor.into_inner()).unwrap();
+        assert_eq!(
+            plan,
+            r#"line 1: ARG VERSION=1.0
+line 2: FROM alpine
+line 3: ARG VERSION
+line 4: ENV APP=/opt/app
+line 5: WORKDIR $APP
+  create directory /opt/app
+line 6: RUN echo $VERSION > version.txt
+  run in /opt/app with APP=/opt/app VERSION=2.0: echo $VERSION > version.txt
+line 7: ADD --chmod=755 https://example.com/tool-${VERSION}.sh /usr/local/bin/tool
+  create directory /usr/local/bin
+  download https://example.com/tool-2.0.sh to /usr/local/bin/tool (mode 755)
+"#
+        );
+        assert!(!Path::new("/opt/app").exists());
+
+        // The files of an earlier stage do not exist, since it never ran
+        fs::write(
+            &dockerfile_path,
+            "FROM alpine AS build\nRUN make\nFROM alpine\nCOPY --from=build /out/bin /usr/local/bin/",
+        )
+        .unwrap();
+        let mut executor = crate::PlanExecutor::new(Vec::new());
+        crate::run_with_executor(&options, &mut executor).expect("Plan failed");
+        let plan = String::from_utf8(executor.into_inner()).unwrap();
+        assert!(
+            plan.ends_with("line 4: COPY --from=build /out/bin /usr/local/bin/\n  copy build:/out/bin to /usr/local/bin\n"),
+            "{}",
+            plan
+        );
+        let output = cargo_command()
+            .args([
+                "run",
+                "--",
+                "--stage-dir",
+                test_dir.join("stages").to_str().unwrap(),
+                "plan",
+                "-f",
+                dockerfile_path.to_str().unwrap(),
+            ])
+            .output()
+            .expect("Failed to execute command");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(!test_dir.join("stages").exists());
+
+        fs::write(&dockerfile_path, "FROM alpine\nENV").unwrap();
+        let output = cargo_command()
+            .args(["run", "--", "plan", "-f", dockerfile_path.to_str().unwrap()])
+            .output()
+            .expect("Failed to execute command");
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("line 2"));
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_export_bash() {
+        // Test that an exported script runs the Dockerfile's steps with bash alone
+        let dockerfile_content = r#"ARG VERSION=1.0
+FROM alpine AS base
+ARG VERSION
+ARG MODE=debug
+ENV GREETING="hello world" LITERAL='$HOME'
+WORKDIR out
+RUN echo "$VERSION $MODE $GREETING $LITERAL" > version.txt
+COPY <<EOF conf/app.conf
+version=$VERSION
+EOF
+COPY input.txt .
+EXPOSE 80
+
+FROM base AS final
+ENV STAGE=final
+RUN ["sh", "-c", "echo $STAGE $GREETING > final.txt"]"#;
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "export_bash");
+        fs::write(test_dir.join("input.txt"), "input").unwrap();
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            ..Options::default()
+        };
+        let script = crate::export(&options, crate::ExportFormat::Bash).expect("Export failed");
+        assert!(script.starts_with("#!/usr/bin/env bash\n"));
+        assert!(script.contains("set -euo pipefail\n"));
+        for block in [
+            "# line 1: ARG VERSION=1.0\ndfrun_arg VERSION 1.0\n",
+            "# line 3: ARG VERSION\ndfrun_arg VERSION\nexport VERSION\n",
+            "export GREETING=\"hello world\"\nexport LITERAL=\"\\$HOME\"\n",
+            "# line 6: WORKDIR out\nmkdir -p out\ncd out\n",
+            "# line 12: EXPOSE 80\n# Nothing to run\n",
+            "# line 14: FROM base AS final\n(\nsource \"$dfrun_stages/0\"\n",
+            "sh -c 'echo $STAGE $GREETING > final.txt'\n",
+        ] {
+            assert!(
+                script.contains(block),
+                "missing {:?} in:\n{}",
+                block,
+                script
+            );
+        }
+
+        let script_path = test_dir.join("setup.sh");
+        fs::write(&script_path, &script).unwrap();
+        let output = Command::new("bash")
+            .arg("setup.sh")
+            .current_dir(&test_dir)
+            .env("VERSION", "2.0")
+            .env_remove("MODE")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to run script");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let out = test_dir.join("out");
+        assert_eq!(
+            fs::read_to_string(out.join("version.txt")).unwrap(),
+            "2.0 debug hello world $HOME\n"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("conf/app.conf")).unwrap(),
+            "version=2.0\n"
+        );
+        assert_eq!(fs::read_to_string(out.join("input.txt")).unwrap(), "input");
+        // The final stage starts from the base stage's ENV and WORKDIR
+        assert_eq!(
+            fs::read_to_string(out.join("final.txt")).unwrap(),
+            "final hello world\n"
+        );
+
+        // Copying from an image cannot be exported
+        fs::write(
+            &dockerfile_path,
+            "FROM alpine\nCOPY --from=nginx:latest /etc/nginx /etc/nginx",
+        )
+        .unwrap();
+        match crate::export(&options, crate::ExportFormat::Bash) {
+            Err(Error::Export(e)) => assert_eq!(e.line, 2),
+            other => panic!("Expected an export error, got {:?}", other),
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_export_bash_runs_commands_like_dfrun() {
+        // Test that RUN commands in an exported script run outside its set -euo pipefail,
+        // and that unset variables in other instructions expand to empty strings
+        let dockerfile_content = r#"RUN echo "v=$UNSET_VAR"; false; echo after
+RUN false | true; echo piped
+RUN cd /
+ENV LD_LIBRARY_PATH=/opt/lib:$UNSET_VAR NAME=${UNSET_VAR%.gz}x${UNSET_VAR:-$ALSO_UNSET}
+RUN echo "$LD_LIBRARY_PATH $NAME"
+RUN echo done > done.txt"#;
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "export_bash_run");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            ..Options::default()
+        };
+        let script = crate::export(&options, crate::ExportFormat::Bash).expect("Export failed");
+
+        fs::write(test_dir.join("setup.sh"), &script).unwrap();
+        let output = Command::new("bash")
+            .arg("setup.sh")
+            .current_dir(&test_dir)
+            .env_remove("UNSET_VAR")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to run script");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "v=\nafter\npiped\n/opt/lib: x\n"
+        );
+        // A cd in one RUN does not carry over to the next
+        assert!(test_dir.join("done.txt").exists());
+
+        // Run the Makefile where make is installed, which uses the same flags
+        if Command::new("make").arg("--version").output().is_ok() {
+            let makefile =
+                crate::export(&options, crate::ExportFormat::Make).expect("Export failed");
+            fs::write(test_dir.join("Makefile"), &makefile).unwrap();
+            let output = Command::new("make")
+                .args(["--silent"])
+                .current_dir(&test_dir)
+                .env_remove("UNSET_VAR")
+                .output()
+                .expect("Failed to run make");
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            assert!(String::from_utf8_lossy(&output.stdout).contains("/opt/lib: x\n"));
+        }
+
+        // Only a required variable stops the script
+        fs::write(&dockerfile_path, "WORKDIR ${UNSET_VAR:?}").unwrap();
+        let script = crate::export(&options, crate::ExportFormat::Bash).expect("Export failed");
+        fs::write(test_dir.join("setup.sh"), &script).unwrap();
+        let status = Command::new("bash")
+            .arg("setup.sh")
+            .current_dir(&test_dir)
+            .env_remove("UNSET_VAR")
+            .stdin(Stdio::null())
+            .status()
+            .expect("Failed to run script");
+        assert!(!status.success());
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_export_make_and_just() {
+        // Test that stages become targets depending on each other, with ARGs as variables
+        let dockerfile_content = r#"ARG VERSION=1.0
+FROM alpine AS base
+ARG VERSION
+ARG USER_NAME
+ENV GREETING=hello
+WORKDIR out
+RUN echo "$VERSION $USER_NAME" > version.txt
+
+FROM alpine AS unused
+RUN exit 1
+
+FROM base AS final
+RUN echo "$GREETING ${VERSION}" > final.txt"#;
+        let (test_dir, dockerfile_path) = create_test_dockerfile(dockerfile_content, "export_make");
+        let options = Options {
+            dockerfile: dockerfile_path.clone(),
+            ..Options::default()
+        };
+
+        let makefile = crate::export(&options, crate::ExportFormat::Make).expect("Export failed");
+        for block in [
+            ".DEFAULT_GOAL := final\n",
+            "# ARG VERSION=1.0\nVERSION ?= 1.0\nexport VERSION\n",
+            "# ARG USER_NAME\nUSER_NAME ?=\nexport USER_NAME\n",
+            ".PHONY: base final\n",
+            "# line 2: FROM alpine AS base\nbase:\n",
+            "\t: \"$${USER_NAME:?No value provided for ARG USER_NAME}\"\n",
+            "# line 12: FROM base AS final\nfinal: base\n",
+            // The final stage repeats the ENV and WORKDIR it starts from
+            "\t# line 5: ENV GREETING=hello\n\texport GREETING=hello\n",
+            "\tbash -c 'echo \"$$GREETING $${VERSION}\" > final.txt'\n",
+        ] {
+            assert!(
+                makefile.contains(block),
+                "missing {:?} in:\n{}",
+                block,
+                makefile
+            );
+        }
+        assert!(!makefile.contains("exit 1"));
+
+        let justfile = crate::export(&options, crate::ExportFormat::Just).expect("Export failed");
+        for block in [
+            "set export\n",
+            "VERSION := env_var_or_default(\"VERSION\", \"1.0\")\n",
+            "default: final\n",
+            "final: base\n    #!/usr/bin/env bash\n    set -euo pipefail\n",
+            "    export GREETING=hello\n",
+        ] {
+            assert!(
+                justfile.contains(block),
+                "missing {:?} in:\n{}",
+                block,
+                justfile
+            );
+        }
+
+        // Run the Makefile where make is installed
+        if Command::new("make").arg("--version").output().is_ok() {
+            fs::write(test_dir.join("Makefile"), &makefile).unwrap();
+            let output = Command::new("make")
+                .args(["VERSION=2.0", "USER_NAME=dev"])
+                .current_dir(&test_dir)
+                .output()
+                .expect("Failed to run make");
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let out = test_dir.join("out");
+            assert_eq!(
+                fs::read_to_string(out.join("version.txt")).unwrap(),
+                "2.0 dev\n"
+            );
+            assert_eq!(
+                fs::read_to_string(out.join("final.txt")).unwrap(),
+                "hello 2.0\n"
+            );
+        }
+
+        // A recipe does not see ARGs that only other stages declare
+        let dir = fs::canonicalize(&test_dir).unwrap();
+        fs::write(
+            &dockerfile_path,
+            format!(
+                "FROM alpine AS tools\nARG TOOL=make\nRUN echo \"$TOOL\" > tool.txt\n\n\
+                 FROM alpine AS final\nCOPY --from=tools {}/tool.txt copied/\n\
+                 RUN echo \"tool=$TOOL\" > final.txt",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        let makefile = crate::export(&options, crate::ExportFormat::Make).expect("Export failed");
+        let justfile = crate::export(&options, crate::ExportFormat::Just).expect("Export failed");
+        for (file, block) in [
+            (
+                &makefile,
+                "\t# ARGs this stage does not declare\n\tunset TOOL\n",
+            ),
+            (
+                &justfile,
+                "    # ARGs this stage does not declare\n    unset TOOL\n",
+            ),
+        ] {
+            assert!(file.contains(block), "missing {:?} in:\n{}", block, file);
+        }
+        assert_eq!(makefile.matches("unset TOOL").count(), 1);
+        if Command::new("make").arg("--version").output().is_ok() {
+            fs::write(test_dir.join("Makefile"), &makefile).unwrap();
+            let output = Command::new("make")
+                .current_dir(&test_dir)
+                .output()
+                .expect("Failed to run make");
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            assert_eq!(
+                fs::read_to_string(test_dir.join("copied/tool.txt")).unwrap(),
+                "make\n"
+            );
+            assert_eq!(
+                fs::read_to_string(test_dir.join("final.txt")).unwrap(),
+                "tool=\n"
+            );
+        }
+
+        cleanup_test_dir(test_dir);
+    }
+
+    #[test]
+    fn test_export_github_actions() {
+        // Test that instructions become steps of one job, with ARGs as inputs
+        let dockerfile_content = r#"ARG VERSION=1.0
+FROM alpine AS base
+ARG VERSION
+ARG USER_NAME
+ENV GREETING=hello
+ENV MESSAGE="$GREETING $VERSION" PATH=$PATH:/opt/bin
+WORKDIR out
+RUN echo "$MESSAGE" '${{ literal }}' > version.txt
+COPY version.txt copy.txt
+
+FROM base AS final
+WORKDIR sub
+RUN echo "$GREETING" > final.txt"#;
+        let (test_dir, dockerfile_path) =
+            create_test_dockerfile(dockerfile_content, "export_github");
+        let options = Options {
+            dockerfile: dockerfile_path,
+            ..Options::default()
+        };
+
+        let workflow =
+            crate::export(&options, crate::ExportFormat::GithubActions).expect("Export failed");
+        for block in [
+        "  workflow_dispatch:\n    inputs:\n      VERSION:\n        description: \"ARG VERSION=1.0\"\n        required: false\n        type: string\n        default: \"1.0\"\n",
+        "      USER_NAME:\n        description: \"ARG USER_NAME\"\n        required: true\n",
+        "  workflow_call:\n    inputs:\n",
+        "jobs:\n  final:\n    runs-on: ubuntu-latest\n",
+        "      - uses: actions/checkout@v4\n      # line 2: FROM alpine AS base\n",
+        "      - name: \"line 7: WORKDIR out\"\n        env:\n          DFRUN_WORKDIR: out\n        run: |\n          mkdir -p \"$DFRUN_WORKDIR\"\n",
+        // Values are substituted into expressions, except those bash has to expand
+        "        working-directory: out\n        env:\n          VERSION: \"${{ inputs.VERSION }}\"\n          USER_NAME: \"${{ inputs.USER_NAME }}\"\n          GREETING: hello\n          MESSAGE: \"hello ${{ inputs.VERSION }}\"\n",
+        "          export PATH=\"${PATH-}:/opt/bin\"\n          bash -c 'echo \"$MESSAGE\" '\\''${{ '${{' }} literal }}'\\'' > version.txt'\n",
+        "          dfrun_copy \"$GITHUB_WORKSPACE\"/version.txt copy.txt\n",
+        // The final stage keeps the ENV and WORKDIR of its base but not its ARGs
+        "      # line 11: FROM base AS final\n      - name: \"line 12: WORKDIR sub\"\n        env:\n          DFRUN_WORKDIR: out/sub\n",
+        "        working-directory: out/sub\n        env:\n          GREETING: hello\n          MESSAGE: \"hello ${{ inputs.VERSION }}\"\n        run: |\n          export PATH=\"${PATH-}:/opt/bin\"\n          bash -c 'echo \"$GREETING\" > final.txt'\n",
+    ] {
+        assert!(
+            workflow.contains(block),
+            "missing {:?} in:\n{}",
+            block,
+            workflow
+        );
+    }
+
+        cleanup_test_dir(test_dir);
+    }
 }